@@ -0,0 +1,421 @@
+use std::{fs::File, io::{self, Read}, path::{Path, PathBuf}};
+use crate::dump;
+use crate::util;
+use crate::pakinterface::{self, MemoryStream, PakError, PakInterface, ResourceNode, ResourceChild, ResourceType, Severity, UuidMatch};
+#[cfg(test)]
+mod tests;
+
+const USAGE: &str = "Usage:
+    pakedit [pak]                                   interactive shell
+    pakedit list <pak> [node/path]                  list the children of a node
+    pakedit tree <pak>                              print the whole tree
+    pakedit extract <pak> <entry> [-o <file>]       extract a single entry
+    pakedit replace <pak> <entry> <file> -o <out>   replace an entry and save as a new pak
+    pakedit replace <pak> <entry> <file> --in-place replace an entry in the existing pak
+    pakedit extract-all <pak> [-o <dir>] [-j <n>]   extract every entry and a manifest with n workers
+    pakedit pack <dir> -o <out>                     build a pak from an extracted directory
+    pakedit patch <pak> <dir> -o <out>              replace every entry with a file in a dump directory
+    pakedit patch <pak> <dir> --in-place            patch the existing pak instead
+    pakedit verify <pak>                            check the structure of a pak
+    pakedit find-uuid <pak> <guid>                  list the entries with a uuid
+
+Saves are verified before they replace <out>, which may be the input pak,
+add --backup to keep the previous <out> as <out>.bak.
+
+--in-place rewrites entries where they are, entries of the root node that grew
+are moved to the end of the pak. Entries inside nested nodes can't grow past
+their old size, save those to a new pak with -o instead.
+
+Entries inside nested nodes are addressed as node/entry, pass - as <pak> to read it from stdin.";
+
+/// Subcommands understood by `run`
+pub const COMMANDS: [&str; 9] = ["list", "tree", "extract", "extract-all", "pack", "replace", "patch", "verify", "find-uuid"];
+
+/// Options that take a value
+const VALUE_OPTIONS: [&str; 4] = ["-o", "--output", "-j", "--jobs"];
+
+#[derive(Debug)]
+enum CommandError {
+    /// Bad command line, usage is printed
+    Usage(String),
+    /// Command failed
+    Failed(String)
+}
+
+impl From<io::Error> for CommandError {
+    fn from(error: io::Error) -> Self {
+        CommandError::Failed(error.to_string())
+    }
+}
+
+impl From<PakError> for CommandError {
+    fn from(error: PakError) -> Self {
+        CommandError::Failed(error.to_string())
+    }
+}
+
+type CommandResult = Result<(), CommandError>;
+
+#[derive(Debug, Default)]
+struct Arguments {
+    positional: Vec<String>,
+    output: Option<String>,
+    jobs: Option<usize>,
+    in_place: bool,
+    backup: bool
+}
+
+impl Arguments {
+    fn parse(args: &[String]) -> Result<Self, CommandError> {
+        let mut arguments = Arguments::default();
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            if VALUE_OPTIONS.contains(&arg.as_str()) {
+                let value = iter.next().ok_or_else(|| CommandError::Usage(format!("{} takes a value", arg)))?;
+                if arg == "-j" || arg == "--jobs" {
+                    let jobs = value.parse().map_err(|_| CommandError::Usage(format!("{} takes a number", arg)))?;
+                    arguments.jobs = Some(jobs);
+                } else {
+                    arguments.output = Some(value.clone());
+                }
+            } else if arg == "--in-place" {
+                arguments.in_place = true;
+            } else if arg == "--backup" {
+                arguments.backup = true;
+            } else if arg.starts_with("-") && arg.len() > 1 {
+                return Err(CommandError::Usage(format!("Unknown option {}", arg)));
+            } else {
+                arguments.positional.push(arg.clone());
+            }
+        }
+        Ok(arguments)
+    }
+
+    /// Checks the positional argument count is in range
+    fn expect(&self, min: usize, max: usize) -> CommandResult {
+        if self.positional.len() < min || self.positional.len() > max {
+            Err(CommandError::Usage("Wrong number of arguments".to_string()))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Is `name` a subcommand rather than a pak to open in the shell?
+pub fn is_command(name: &str) -> bool {
+    COMMANDS.contains(&name) || name == "help" || name == "--help"
+}
+
+/// Runs a single command to completion, returns the process exit code
+pub fn run(args: &[String]) -> i32 {
+    let result = match Arguments::parse(&args[1..]) {
+        Ok(arguments) => match args[0].as_str() {
+            "list" => list(&arguments),
+            "tree" => tree(&arguments),
+            "extract" => extract(&arguments),
+            "extract-all" => extract_all(&arguments),
+            "pack" => pack(&arguments),
+            "replace" => replace(&arguments),
+            "patch" => patch(&arguments),
+            "verify" => verify(&arguments),
+            "find-uuid" => find_uuid(&arguments),
+            "help" | "--help" => {
+                println!("{}", USAGE);
+                Ok(())
+            }
+            _ => Err(CommandError::Usage(format!("Unknown command {}", args[0])))
+        },
+        Err(error) => Err(error)
+    };
+
+    match result {
+        Ok(()) => 0,
+        Err(CommandError::Usage(message)) => {
+            eprintln!("{}\n\n{}", message, USAGE);
+            2
+        }
+        Err(CommandError::Failed(message)) => {
+            eprintln!("Error: {}", message);
+            1
+        }
+    }
+}
+
+/// Would writing to `output` clobber `input`?
+pub fn is_same_file(input: &str, output: &str) -> bool {
+    match (std::fs::canonicalize(input), std::fs::canonicalize(output)) {
+        (Ok(input), Ok(output)) => input == output,
+        _ => Path::new(input) == Path::new(output)
+    }
+}
+
+/// Reads a pak, "-" reads it from stdin
+fn read_pak(path: &str) -> Result<PakInterface, CommandError> {
+    if path == "-" {
+        let mut data = Vec::new();
+        io::stdin().read_to_end(&mut data).map_err(|error| CommandError::Failed(format!("Failed to read stdin, {}", error)))?;
+        return PakInterface::open_from(MemoryStream::new(data)).map_err(|error| CommandError::Failed(format!("Failed to read pack file, {}", error)));
+    }
+    if pakinterface::rollback_journal(Path::new(path))? {
+        println!("Rolled back an interrupted in-place save of \"{}\"", path);
+    }
+    let file = File::open(path).map_err(|error| CommandError::Failed(format!("Failed to open pack file, {}", error)))?;
+    PakInterface::open(file).map_err(|error| CommandError::Failed(format!("Failed to read pack file, {}", error)))
+}
+
+/// Opens a pak, problems with its layout are printed but don't stop the command
+fn open_pak(path: &str) -> Result<PakInterface, CommandError> {
+    let pak = read_pak(path)?;
+    for diagnostic in pak.validate_loaded() {
+        eprintln!("{}", diagnostic);
+    }
+    Ok(pak)
+}
+
+/// Splits a path into the name of the child of `node` it starts with and the rest of the path,
+/// names containing '/' are matched before splitting
+fn split_path<'a>(node: &ResourceNode, path: &'a str) -> (&'a str, Option<&'a str>) {
+    if node.find_child(path).is_some() {
+        return (path, None);
+    }
+    match path.find('/') {
+        Some(index) => (&path[..index], Some(&path[index + 1..])),
+        None => (path, None)
+    }
+}
+
+fn find_node<'a>(node: &'a ResourceNode, path: &str) -> Result<&'a ResourceNode, CommandError> {
+    node.load()?;
+    if path.is_empty() {
+        return Ok(node);
+    }
+    let (name, rest) = split_path(node, path);
+    match node.find_child(name).map(|child| child.contents()) {
+        Some(ResourceType::Node(child_node)) => find_node(child_node, rest.unwrap_or("")),
+        Some(_) => Err(CommandError::Failed(format!("\"{}\" is not a node", name))),
+        None => Err(CommandError::Failed(format!("No such node \"{}\"", name)))
+    }
+}
+
+fn find_entry<'a>(node: &'a ResourceNode, path: &str) -> Result<&'a ResourceChild, CommandError> {
+    node.load()?;
+    let (name, rest) = split_path(node, path);
+    let child = node.find_child(name).ok_or_else(|| CommandError::Failed(format!("No such entry \"{}\"", name)))?;
+    match (child.contents(), rest) {
+        (ResourceType::Node(child_node), Some(rest)) => find_entry(child_node, rest),
+        (_, Some(_)) => Err(CommandError::Failed(format!("\"{}\" is not a node", name))),
+        (_, None) => Ok(child)
+    }
+}
+
+fn find_entry_mut<'a>(node: &'a mut ResourceNode, path: &str) -> Result<&'a mut ResourceChild, CommandError> {
+    node.load()?;
+    let (name, rest) = split_path(node, path);
+    let child = node.find_child_mut(name).ok_or_else(|| CommandError::Failed(format!("No such entry \"{}\"", name)))?;
+    match rest {
+        Some(rest) => {
+            if let ResourceType::Node(child_node) = child.contents_mut() {
+                find_entry_mut(child_node, rest)
+            } else {
+                Err(CommandError::Failed(format!("\"{}\" is not a node", name)))
+            }
+        }
+        None => Ok(child)
+    }
+}
+
+/// Only Data and Resource entries have data we can read or replace
+fn check_has_data(path: &str, child: &ResourceChild) -> CommandResult {
+    match child.contents() {
+        ResourceType::Data | ResourceType::Resource(_) => Ok(()),
+        contents => Err(CommandError::Failed(format!("\"{}\" is a {}, not data", path, contents)))
+    }
+}
+
+fn list(args: &Arguments) -> CommandResult {
+    args.expect(1, 2)?;
+    let pak = open_pak(&args.positional[0])?;
+    let path = args.positional.get(1).map(|path| path.as_str()).unwrap_or("");
+    let node = find_node(pak.get_root_node(), path)?;
+    for child in node.children() {
+        println!("{}, {}", child.name(), child.contents());
+    }
+    Ok(())
+}
+
+fn print_tree(node: &ResourceNode, depth: usize) {
+    for child in node.children() {
+        println!("{}{}, {}, {}", "- ".repeat(depth), child.name(), child.contents(), child.size());
+        if let ResourceType::Node(child_node) = child.contents() {
+            print_tree(child_node, depth + 1);
+        }
+    }
+}
+
+fn tree(args: &Arguments) -> CommandResult {
+    args.expect(1, 1)?;
+    let pak = open_pak(&args.positional[0])?;
+    print_tree(pak.get_root_node(), 0);
+    Ok(())
+}
+
+fn extract(args: &Arguments) -> CommandResult {
+    args.expect(2, 2)?;
+    let pak = open_pak(&args.positional[0])?;
+    let path = &args.positional[1];
+    let child = find_entry(pak.get_root_node(), path)?;
+    check_has_data(path, child)?;
+
+    let output = match &args.output {
+        Some(output) => output.clone(),
+        None => child.name().rsplit(|c| c == '\\' || c == '/').next().unwrap().to_string()
+    };
+    child.with_data(|data| std::fs::write(&output, data))??;
+    println!("Extracted \"{}\" to \"{}\"", path, output);
+    Ok(())
+}
+
+fn extract_all(args: &Arguments) -> CommandResult {
+    args.expect(1, 1)?;
+    let input = &args.positional[0];
+    let pak = open_pak(input)?;
+
+    let output = match &args.output {
+        Some(output) => PathBuf::from(output),
+        None => PathBuf::from(Path::new(input).file_stem().unwrap_or_default())
+    };
+    let workers = args.jobs.unwrap_or_else(dump::default_workers);
+    dump::extract_all_parallel(pak.get_root_node(), &output, workers, |done, total| {
+        eprint!("\rWriting entries {}/{}", done, total);
+        if done == total {
+            eprintln!();
+        }
+    })?;
+    println!("Extracted \"{}\" to \"{}\"", input, output.display());
+    Ok(())
+}
+
+fn pack(args: &Arguments) -> CommandResult {
+    args.expect(1, 1)?;
+    let output = args.output.as_ref().ok_or_else(|| CommandError::Usage("Pack requires an output pak".to_string()))?;
+    let mut pak = dump::pack(Path::new(&args.positional[0]))?;
+    pak.save_as(Path::new(output), None, args.backup)?;
+    println!("Packed \"{}\" as \"{}\"", args.positional[0], output);
+    Ok(())
+}
+
+/// Where a modified pak goes, either a full save to a file, which may be the input, or a patch
+/// of the input with --in-place
+fn output_pak(args: &Arguments, command: &str) -> Result<Option<String>, CommandError> {
+    match (&args.output, args.in_place) {
+        (Some(_), true) => Err(CommandError::Usage("--in-place can't be used with an output pak".to_string())),
+        (None, false) => Err(CommandError::Usage(format!("{} requires an output pak or --in-place", command))),
+        (None, true) if args.backup => Err(CommandError::Usage("--backup can't be used with --in-place".to_string())),
+        (output, _) => Ok(output.clone())
+    }
+}
+
+fn save_pak(pak: &mut PakInterface, args: &Arguments, output: &Option<String>) -> CommandResult {
+    match output {
+        Some(output) => {
+            let backup = pak.save_as(Path::new(output), None, args.backup)?;
+            println!("Saved as \"{}\"", output);
+            if let Some(backup) = backup {
+                println!("Kept the previous version as \"{}\"", backup.display());
+            }
+        },
+        None => {
+            let input = &args.positional[0];
+            pak.save_in_place(Path::new(input))?;
+            println!("Saved \"{}\" in place", input);
+        }
+    }
+    Ok(())
+}
+
+fn replace(args: &Arguments) -> CommandResult {
+    args.expect(3, 3)?;
+    let output = output_pak(args, "Replace")?;
+    let input = &args.positional[0];
+
+    let mut pak = open_pak(input)?;
+    let path = &args.positional[1];
+    let data = std::fs::read(&args.positional[2])?;
+    {
+        let child = find_entry_mut(pak.get_root_node_mut(), path)?;
+        check_has_data(path, child)?;
+        child.set_data(data);
+    }
+
+    println!("Replaced \"{}\"", path);
+    save_pak(&mut pak, args, &output)
+}
+
+fn patch(args: &Arguments) -> CommandResult {
+    args.expect(2, 2)?;
+    let output = output_pak(args, "Patch")?;
+    let input = &args.positional[0];
+
+    let mut pak = open_pak(input)?;
+    let report = dump::patch_from_directory(pak.get_root_node_mut(), Path::new(&args.positional[1]))?;
+    println!("{}", report);
+    if report.replaced.is_empty() {
+        return Err(CommandError::Failed("No entries matched".to_string()));
+    }
+
+    save_pak(&mut pak, args, &output)
+}
+
+/// Number of nodes and entries under `node`
+fn count_children(node: &ResourceNode) -> (usize, usize) {
+    let mut counts = (1, 0);
+    for child in node.children() {
+        counts.1 += 1;
+        if let ResourceType::Node(child_node) = child.contents() {
+            let (nodes, entries) = count_children(child_node);
+            counts.0 += nodes;
+            counts.1 += entries;
+        }
+    }
+    counts
+}
+
+fn verify(args: &Arguments) -> CommandResult {
+    args.expect(1, 1)?;
+    let path = &args.positional[0];
+    // anything that stops the pak from being read is reported as a single error
+    let pak = read_pak(path)?;
+
+    let diagnostics = pak.validate();
+    for diagnostic in &diagnostics {
+        println!("{}", diagnostic);
+    }
+    let errors = diagnostics.iter().filter(|diagnostic| diagnostic.severity == Severity::Error).count();
+    let (nodes, entries) = count_children(pak.get_root_node());
+    println!("Checked {} nodes and {} entries, {} errors, {} warnings", nodes, entries, errors, diagnostics.len() - errors);
+
+    if errors > 0 {
+        Err(CommandError::Failed(format!("\"{}\" is damaged", path)))
+    } else {
+        Ok(())
+    }
+}
+
+pub fn print_uuid_matches(matches: &[UuidMatch]) {
+    for entry in matches {
+        println!("{}, {}", if entry.path.is_empty() { "(root)" } else { &entry.path }, entry.class);
+    }
+}
+
+fn find_uuid(args: &Arguments) -> CommandResult {
+    args.expect(2, 2)?;
+    let uuid = util::parse_uuid(&args.positional[1]).ok_or_else(|| CommandError::Usage(format!("Bad uuid \"{}\"", args.positional[1])))?;
+    let pak = open_pak(&args.positional[0])?;
+    let matches = pak.find_by_uuid(uuid);
+    print_uuid_matches(&matches);
+    if matches.is_empty() {
+        Err(CommandError::Failed(format!("No entry has uuid {}", util::Guid(uuid))))
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,95 @@
+//! Argument parsing and exit codes, the commands themselves are covered by the pakinterface tests
+
+use super::*;
+use crate::pakinterface::{NodeBuilder, ResourceHeader};
+
+fn strings(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+}
+
+fn parse(args: &[&str]) -> Result<Arguments, CommandError> {
+    Arguments::parse(&strings(args))
+}
+
+/// A root node with a "dir" node holding "entry", and a data child named "dir/entry" too
+fn test_node() -> ResourceNode {
+    let mut dir = NodeBuilder::new(ResourceHeader::new("ssl_block".to_string(), 2, 0, Vec::new()));
+    dir.add_data("entry".to_string(), vec![1, 2, 3]);
+    let mut root = NodeBuilder::new(ResourceHeader::new("pak".to_string(), 1, 0, Vec::new()));
+    root.add_node("dir".to_string(), dir).unwrap();
+    root.add_data("dir/entry".to_string(), vec![4, 5, 6]);
+    root.build().unwrap()
+}
+
+#[test]
+fn options_are_parsed_anywhere() {
+    let arguments = parse(&["in.pak", "-o", "out.pak", "--jobs", "4", "entry", "--in-place", "--backup", "-"]).unwrap();
+    assert_eq!(arguments.positional, ["in.pak", "entry", "-"]);
+    assert_eq!(arguments.output.as_deref(), Some("out.pak"));
+    assert_eq!(arguments.jobs, Some(4));
+    assert!(arguments.in_place && arguments.backup);
+
+    let arguments = parse(&["--output", "a", "-j", "2"]).unwrap();
+    assert!(arguments.positional.is_empty());
+    assert_eq!((arguments.output.as_deref(), arguments.jobs), (Some("a"), Some(2)));
+
+    for args in &[&["-o"][..], &["in.pak", "--jobs"], &["-j", "many"], &["--force"], &["-x", "in.pak"]] {
+        assert!(matches!(parse(args), Err(CommandError::Usage(_))), "{:?}", args);
+    }
+
+    assert!(parse(&["a", "b"]).unwrap().expect(1, 2).is_ok());
+    assert!(matches!(parse(&["a", "b", "c"]).unwrap().expect(1, 2), Err(CommandError::Usage(_))));
+    assert!(matches!(parse(&[]).unwrap().expect(1, 2), Err(CommandError::Usage(_))));
+}
+
+#[test]
+fn output_pak_needs_exactly_one_destination() {
+    assert_eq!(output_pak(&parse(&["in.pak", "-o", "out.pak"]).unwrap(), "Replace").unwrap().as_deref(), Some("out.pak"));
+    assert_eq!(output_pak(&parse(&["in.pak", "-o", "out.pak", "--backup"]).unwrap(), "Replace").unwrap().as_deref(), Some("out.pak"));
+    assert_eq!(output_pak(&parse(&["in.pak", "--in-place"]).unwrap(), "Replace").unwrap(), None);
+
+    for args in &[&["in.pak"][..], &["in.pak", "-o", "out.pak", "--in-place"], &["in.pak", "--in-place", "--backup"]] {
+        assert!(matches!(output_pak(&parse(args).unwrap(), "Replace"), Err(CommandError::Usage(_))), "{:?}", args);
+    }
+}
+
+#[test]
+fn paths_prefer_names_containing_slashes() {
+    let node = test_node();
+    assert_eq!(split_path(&node, "dir/entry"), ("dir/entry", None));
+    assert_eq!(split_path(&node, "dir/other"), ("dir", Some("other")));
+    assert_eq!(split_path(&node, "dir"), ("dir", None));
+    assert_eq!(split_path(&node, "a/b/c"), ("a", Some("b/c")));
+
+    assert_eq!(find_entry(&node, "dir/entry").unwrap().data().unwrap(), [4, 5, 6]);
+    assert!(find_node(&node, "dir").is_ok());
+    assert!(matches!(find_entry(&node, "dir/missing"), Err(CommandError::Failed(_))));
+    assert!(matches!(find_node(&node, "dir/entry/x"), Err(CommandError::Failed(_))));
+}
+
+#[test]
+fn exit_codes_tell_usage_errors_from_failures() {
+    let pak_file = tempfile::NamedTempFile::new().unwrap();
+    PakInterface::from_root_node(test_node()).save(pak_file.reopen().unwrap()).unwrap();
+    let pak = pak_file.path().to_str().unwrap();
+    let missing = pak_file.path().with_extension("missing");
+    let missing = missing.to_str().unwrap();
+
+    let codes = [
+        (&["verify", pak][..], 0),
+        (&["list", pak, "dir"], 0),
+        (&["help"], 0),
+        (&["verify", missing], 1),
+        (&["list", pak, "nothing"], 1),
+        (&["find-uuid", pak, "00000000-0000-0000-0000-000000000099"], 1),
+        (&["unpack", pak], 2),
+        (&["verify"], 2),
+        (&["verify", pak, "--jobs", "some"], 2),
+        (&["replace", pak, "dir/entry", missing], 2),
+        (&["replace", pak, "dir/entry", missing, "-o", missing, "--in-place"], 2),
+        (&["find-uuid", pak, "99"], 2)
+    ];
+    for (args, code) in &codes {
+        assert_eq!(run(&strings(args)), *code, "{:?}", args);
+    }
+}
@@ -0,0 +1,148 @@
+use std::io;
+use serde::{Serialize, Deserialize};
+use crate::pakinterface::{ResourceHeader, MetaData, MetaDataName};
+use crate::util::{self, Guid};
+
+/// Name of the manifest written to the root of an extracted pak
+pub const MANIFEST_NAME: &str = "manifest.json";
+
+/// Everything needed to rebuild a pak from an extracted directory
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    /// header of the root node
+    pub header: ManifestHeader,
+    pub children: Vec<ManifestEntry>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryType {
+    Data,
+    Link,
+    Node,
+    Resource
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// name as stored in the pak
+    pub name: String,
+    /// node path, the names of the parent nodes and this entry joined by '/'
+    pub path: String,
+    #[serde(rename = "type")]
+    pub entry_type: EntryType,
+    /// file holding the data, relative to the manifest and '/' separated
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    /// resource or node header
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub header: Option<ManifestHeader>,
+    /// sibling this entry links to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link: Option<ManifestLink>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<ManifestEntry>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ManifestLink {
+    pub index: usize,
+    pub name: String
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ManifestHeader {
+    pub class: String,
+    /// GUID
+    pub uuid: String,
+    pub unk0: u32,
+    #[serde(default)]
+    pub meta_data: Vec<ManifestMetaData>
+}
+
+/// A metadata entry, the name is written as text when it is one and as hex otherwise
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ManifestMetaData {
+    pub kind: u32,
+    /// GUID
+    pub type_guid: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub nul_terminated: bool,
+    /// hex
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name_data: Option<String>
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn invalid_hex(value: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Bad hex value \"{}\"", value))
+}
+
+fn from_hex(value: &str) -> io::Result<Vec<u8>> {
+    if value.len() % 2 != 0 || !value.is_ascii() {
+        return Err(invalid_hex(value));
+    }
+    (0..value.len()).step_by(2).map(|index| u8::from_str_radix(&value[index..index + 2], 16).map_err(|_| invalid_hex(value))).collect()
+}
+
+fn parse_uuid(value: &str) -> io::Result<u128> {
+    util::parse_uuid(value).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("Bad GUID \"{}\"", value)))
+}
+
+impl From<&MetaData> for ManifestMetaData {
+    fn from(meta_data: &MetaData) -> Self {
+        let mut entry = ManifestMetaData {
+            kind: meta_data.kind(),
+            type_guid: Guid(meta_data.type_guid()).to_string(),
+            name: None,
+            nul_terminated: false,
+            name_data: None
+        };
+        match meta_data.name() {
+            MetaDataName::Text { name, nul_terminated } => {
+                entry.name = Some(name);
+                entry.nul_terminated = nul_terminated;
+            },
+            MetaDataName::Raw(data) => entry.name_data = Some(to_hex(&data))
+        }
+        entry
+    }
+}
+
+impl ManifestMetaData {
+    pub fn to_meta_data(&self) -> io::Result<MetaData> {
+        let name = match (&self.name, &self.name_data) {
+            (Some(name), None) => MetaDataName::Text { name: name.clone(), nul_terminated: self.nul_terminated },
+            (None, Some(name_data)) => MetaDataName::Raw(from_hex(name_data)?),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Metadata entries need either a name or name_data"))
+        };
+        Ok(MetaData::new(self.kind, parse_uuid(&self.type_guid)?, name.encode()))
+    }
+}
+
+impl ManifestHeader {
+    pub fn to_header(&self) -> io::Result<ResourceHeader> {
+        let meta_data = self.meta_data.iter().map(|entry| entry.to_meta_data()).collect::<io::Result<Vec<_>>>()?;
+        Ok(ResourceHeader::new(self.class.clone(), parse_uuid(&self.uuid)?, self.unk0, meta_data))
+    }
+}
+
+impl From<&ResourceHeader> for ManifestHeader {
+    fn from(header: &ResourceHeader) -> Self {
+        ManifestHeader {
+            class: header.class().clone(),
+            uuid: Guid(*header.uuid()).to_string(),
+            unk0: *header.unk0(),
+            meta_data: header.meta_data().iter().map(ManifestMetaData::from).collect()
+        }
+    }
+}
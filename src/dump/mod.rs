@@ -0,0 +1,347 @@
+use std::{collections::{BTreeMap, HashMap, HashSet}, io, path::{Path, PathBuf}, sync::{Mutex, atomic::{AtomicUsize, Ordering}}};
+use crate::pakinterface::{PakInterface, ResourceNode, ResourceChild, ResourceType, NodeBuilder};
+use crate::util::join_node_path;
+pub mod manifest;
+use manifest::{Manifest, ManifestEntry, ManifestHeader, ManifestLink, EntryType, MANIFEST_NAME};
+
+/// Name a child is dumped under, without the class prefix
+pub fn entry_file_name(name: &str) -> &str {
+    name.rsplit(">\\").next().unwrap().rsplit(":").next().unwrap()
+}
+
+/// Name of the directory a node child is dumped into
+pub fn node_directory_name(name: &str) -> &str {
+    entry_file_name(name).split(".").next().unwrap()
+}
+
+/// Splits a relative dump path into its components, either separator is accepted,
+/// components that would escape the dump directory are dropped
+fn split_components(path: &str) -> impl Iterator<Item = &str> {
+    path.split(|c| c == '\\' || c == '/').filter(|component| !component.is_empty() && *component != "." && *component != "..")
+}
+
+/// Key used to match files to entries, case insensitive as the dumps are made on Windows
+fn match_key<'a, I: Iterator<Item = &'a str>>(components: I) -> String {
+    components.map(|component| component.to_lowercase()).collect::<Vec<_>>().join("/")
+}
+
+/// Result of `patch_from_directory`, entries are identified by their node path
+#[derive(Debug, Default)]
+pub struct PatchReport {
+    /// entries that were replaced and the file they were replaced with
+    pub replaced: Vec<(String, PathBuf)>,
+    /// files that didn't match any entry
+    pub unmatched_files: Vec<PathBuf>,
+    /// files that matched more than one entry, all of them were replaced
+    pub ambiguous: Vec<(PathBuf, Vec<String>)>
+}
+
+fn collect_files(directory: &Path, prefix: &str, files: &mut BTreeMap<String, PathBuf>) -> io::Result<()> {
+    for entry in std::fs::read_dir(directory)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if prefix.is_empty() && name == MANIFEST_NAME {
+            continue;
+        }
+        let relative_path = join_node_path(prefix, &name);
+        if entry.file_type()?.is_dir() {
+            collect_files(&entry.path(), &relative_path, files)?;
+        } else {
+            files.insert(match_key(split_components(&relative_path)), entry.path());
+        }
+    }
+    Ok(())
+}
+
+struct PatchState {
+    files: BTreeMap<String, PathBuf>,
+    matches: BTreeMap<String, Vec<String>>,
+    file_data: BTreeMap<String, Vec<u8>>
+}
+
+/// Manifest entry written for the `occurrence`th child called `name`, entries with the same
+/// name are told apart by their order
+fn manifest_entry<'a>(entries: Option<&'a [ManifestEntry]>, name: &str, occurrence: usize) -> Option<&'a ManifestEntry> {
+    entries?.iter().filter(|entry| entry.name == name).nth(occurrence)
+}
+
+/// Files are found through the manifest if there is one, which knows the names picked for
+/// duplicates, otherwise or for entries missing from it by the entry name
+fn patch_node(node: &mut ResourceNode, directory: &str, node_path: &str, manifest: Option<&[ManifestEntry]>, state: &mut PatchState) -> io::Result<()> {
+    node.load()?;
+    let mut occurrences = HashMap::new();
+    for child in node.children_mut() {
+        let name = child.name().clone();
+        let entry_path = join_node_path(node_path, &name);
+        let occurrence = occurrences.entry(name.clone()).or_insert(0);
+        let manifest_entry = manifest_entry(manifest, &name, *occurrence);
+        *occurrence += 1;
+
+        match child.contents_mut() {
+            ResourceType::Node(child_node) => {
+                let child_directory = join_node_path(directory, node_directory_name(&name));
+                patch_node(child_node, &child_directory, &entry_path, manifest_entry.map(|entry| &entry.children[..]), state)?;
+                continue;
+            },
+            ResourceType::Link(_) => continue, // links follow their target
+            _ => {}
+        }
+
+        let key = match manifest_entry.and_then(|entry| entry.file.as_ref()) {
+            Some(file) => match_key(split_components(file)),
+            None => match_key(split_components(directory).chain(split_components(entry_file_name(&name))))
+        };
+        if let Some(path) = state.files.get(&key) {
+            if !state.file_data.contains_key(&key) {
+                state.file_data.insert(key.clone(), std::fs::read(path)?);
+            }
+            child.set_data(state.file_data[&key].clone());
+            state.matches.entry(key).or_default().push(entry_path);
+        }
+    }
+    Ok(())
+}
+
+/// Replaces every Data and Resource entry that has a file in `directory`, which is
+/// expected to mirror the layout written by `extract_all`, its manifest is used if it has one
+pub fn patch_from_directory(node: &mut ResourceNode, directory: &Path) -> io::Result<PatchReport> {
+    let manifest = match std::fs::read_to_string(directory.join(MANIFEST_NAME)) {
+        Ok(manifest_data) => Some(serde_json::from_str::<Manifest>(&manifest_data).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => None,
+        Err(error) => return Err(error)
+    };
+
+    let mut state = PatchState { files: BTreeMap::new(), matches: BTreeMap::new(), file_data: BTreeMap::new() };
+    collect_files(directory, "", &mut state.files)?;
+    patch_node(node, "", "", manifest.as_ref().map(|manifest| &manifest.children[..]), &mut state)?;
+
+    let mut report = PatchReport::default();
+    for (key, path) in &state.files {
+        match state.matches.get(key) {
+            Some(entries) => {
+                for entry in entries {
+                    report.replaced.push((entry.clone(), path.clone()));
+                }
+                if entries.len() > 1 {
+                    report.ambiguous.push((path.clone(), entries.clone()));
+                }
+            },
+            None => report.unmatched_files.push(path.clone())
+        }
+    }
+    Ok(report)
+}
+
+impl std::fmt::Display for PatchReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for (entry, path) in &self.replaced {
+            writeln!(f, "Replaced \"{}\" with \"{}\"", entry, path.display())?;
+        }
+        for path in &self.unmatched_files {
+            writeln!(f, "No match for \"{}\"", path.display())?;
+        }
+        for (path, entries) in &self.ambiguous {
+            writeln!(f, "\"{}\" matched {} entries:", path.display(), entries.len())?;
+            for entry in entries {
+                writeln!(f, "    {}", entry)?;
+            }
+        }
+        write!(f, "{} entries replaced, {} files unmatched, {} ambiguous", self.replaced.len(), self.unmatched_files.len(), self.ambiguous.len())
+    }
+}
+
+/// Joins a node path and a child name
+/// Picks a relative path no other entry in the node uses, the dumps have to work on
+/// case insensitive file systems
+fn unique_path(used_paths: &mut HashSet<String>, components: Vec<&str>) -> Vec<String> {
+    let mut components: Vec<String> = components.iter().map(|component| component.to_string()).collect();
+    if components.is_empty() {
+        components.push("unnamed".to_string());
+    }
+
+    let base_name = components.last().unwrap().clone();
+    let mut copy = 0;
+    while !used_paths.insert(match_key(components.iter().map(|component| component.as_str()))) {
+        copy += 1;
+        *components.last_mut().unwrap() = format!("{}#{}", base_name, copy);
+    }
+    components
+}
+
+/// A data or resource child and the file it's extracted to
+struct ExtractJob<'a> {
+    child: &'a ResourceChild,
+    file_path: PathBuf
+}
+
+/// Builds the manifest entries for `node`, creating the directories, the data is written
+/// later from the jobs
+fn extract_node<'a>(node: &'a ResourceNode, directory: &Path, relative_path: &str, node_path: &str, jobs: &mut Vec<ExtractJob<'a>>) -> io::Result<Vec<ManifestEntry>> {
+    node.load()?;
+    std::fs::create_dir_all(directory)?;
+    let mut used_paths = HashSet::new();
+    used_paths.insert(MANIFEST_NAME.to_string());
+
+    let mut entries = Vec::new();
+    for child in node.children() {
+        let mut entry = ManifestEntry {
+            name: child.name().clone(),
+            path: join_node_path(node_path, child.name()),
+            entry_type: EntryType::Data,
+            file: None,
+            header: None,
+            link: None,
+            children: Vec::new()
+        };
+
+        match child.contents() {
+            ResourceType::Node(child_node) => {
+                let components = unique_path(&mut used_paths, vec![node_directory_name(child.name())]);
+                let child_relative_path = join_node_path(relative_path, &components[0]);
+                entry.entry_type = EntryType::Node;
+                entry.header = Some(ManifestHeader::from(child_node.header()));
+                entry.children = extract_node(child_node, &directory.join(&components[0]), &child_relative_path, &entry.path, jobs)?;
+            },
+            ResourceType::Link(index) => {
+                entry.entry_type = EntryType::Link;
+                entry.link = Some(ManifestLink { index: *index, name: node.children()[*index].name().clone() });
+            },
+            contents => {
+                if let ResourceType::Resource(header) = contents {
+                    entry.entry_type = EntryType::Resource;
+                    entry.header = Some(ManifestHeader::from(header));
+                }
+
+                let components = unique_path(&mut used_paths, split_components(entry_file_name(child.name())).collect());
+                let mut file_path = directory.to_path_buf();
+                for component in &components {
+                    file_path.push(component);
+                }
+                std::fs::create_dir_all(file_path.parent().unwrap())?;
+                jobs.push(ExtractJob { child: child, file_path: file_path });
+                entry.file = Some(join_node_path(relative_path, &components.join("/")));
+            }
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+fn write_job(job: &ExtractJob) -> io::Result<()> {
+    job.child.with_data(|data| std::fs::write(&job.file_path, data))?
+        .map_err(|error| io::Error::new(error.kind(), format!("Failed to write \"{}\", {}", job.file_path.display(), error)))
+}
+
+/// Writes the jobs from `workers` threads, each thread takes the next unwritten job until
+/// they're all done or one fails, `progress` is never called concurrently
+fn run_jobs<F: Fn(usize, usize) + Sync>(jobs: &[ExtractJob], workers: usize, progress: &F) -> io::Result<()> {
+    let next = AtomicUsize::new(0);
+    let done = Mutex::new(0);
+    let failure = Mutex::new(None);
+
+    std::thread::scope(|scope| {
+        for _ in 0..workers.min(jobs.len()) {
+            scope.spawn(|| {
+                loop {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    if index >= jobs.len() {
+                        break;
+                    }
+                    if let Err(error) = write_job(&jobs[index]) {
+                        // stop the other workers picking up new jobs
+                        next.store(jobs.len(), Ordering::Relaxed);
+                        failure.lock().unwrap_or_else(|error| error.into_inner()).get_or_insert(error);
+                        break;
+                    }
+                    // under the lock so progress is reported in order
+                    let mut done = done.lock().unwrap_or_else(|error| error.into_inner());
+                    *done += 1;
+                    progress(*done, jobs.len());
+                }
+            });
+        }
+    });
+
+    match failure.into_inner().unwrap_or_else(|error| error.into_inner()) {
+        Some(error) => Err(error),
+        None => Ok(())
+    }
+}
+
+/// Number of workers used when none is given, one per core
+pub fn default_workers() -> usize {
+    std::thread::available_parallelism().map(|count| count.get()).unwrap_or(1)
+}
+
+/// Writes every entry under `node` to `directory` and a manifest describing the tree
+pub fn extract_all(node: &ResourceNode, directory: &Path) -> io::Result<Manifest> {
+    extract_all_parallel(node, directory, 0, |_, _| {})
+}
+
+/// `extract_all` with the entry data written by `workers` threads, 0 for `default_workers`,
+/// `progress` is called with the number of entries written and the total after each one
+pub fn extract_all_parallel<F: Fn(usize, usize) + Sync>(node: &ResourceNode, directory: &Path, workers: usize, progress: F) -> io::Result<Manifest> {
+    let mut jobs = Vec::new();
+    let manifest = Manifest {
+        header: ManifestHeader::from(node.header()),
+        children: extract_node(node, directory, "", "", &mut jobs)?
+    };
+
+    let workers = if workers == 0 { default_workers() } else { workers };
+    run_jobs(&jobs, workers, &progress)?;
+
+    let manifest_data = serde_json::to_string_pretty(&manifest).map_err(|error| io::Error::new(io::ErrorKind::Other, error))?;
+    std::fs::write(directory.join(MANIFEST_NAME), manifest_data)?;
+    Ok(manifest)
+}
+
+fn invalid_manifest(entry: &ManifestEntry, message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("\"{}\" {}", entry.path, message))
+}
+
+fn read_entry_file(directory: &Path, entry: &ManifestEntry) -> io::Result<Vec<u8>> {
+    let file = entry.file.as_ref().ok_or_else(|| invalid_manifest(entry, "has no file"))?;
+    let mut file_path = directory.to_path_buf();
+    for component in split_components(file) {
+        file_path.push(component);
+    }
+    std::fs::read(&file_path).map_err(|error| io::Error::new(error.kind(), format!("Failed to read \"{}\", {}", file_path.display(), error)))
+}
+
+fn entry_header(entry: &ManifestEntry) -> io::Result<crate::pakinterface::ResourceHeader> {
+    entry.header.as_ref().ok_or_else(|| invalid_manifest(entry, "has no header"))?.to_header()
+}
+
+fn pack_node(directory: &Path, builder: &mut NodeBuilder, entries: &[ManifestEntry]) -> io::Result<()> {
+    for entry in entries {
+        let name = entry.name.clone();
+        match entry.entry_type {
+            EntryType::Data => {
+                builder.add_data(name, read_entry_file(directory, entry)?);
+            },
+            EntryType::Resource => {
+                builder.add_resource(name, entry_header(entry)?, read_entry_file(directory, entry)?);
+            },
+            EntryType::Node => {
+                let mut child_builder = NodeBuilder::new(entry_header(entry)?);
+                pack_node(directory, &mut child_builder, &entry.children)?;
+                builder.add_node(name, child_builder)?;
+            },
+            EntryType::Link => {
+                let link = entry.link.as_ref().ok_or_else(|| invalid_manifest(entry, "has no link target"))?;
+                builder.add_link(name, link.index);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Builds a pak from a directory written by `extract_all`
+pub fn pack(directory: &Path) -> io::Result<PakInterface> {
+    let manifest_data = std::fs::read_to_string(directory.join(MANIFEST_NAME))?;
+    let manifest: Manifest = serde_json::from_str(&manifest_data).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+
+    let mut builder = NodeBuilder::new(manifest.header.to_header()?);
+    pack_node(directory, &mut builder, &manifest.children)?;
+    Ok(PakInterface::from_root_node(builder.build()?))
+}
@@ -2,10 +2,12 @@
 #![feature(const_generics)]
 #![feature(seek_convenience)]
 #![feature(seek_stream_len)]
-use std::{env, fs::File, io::{self, Write, Read}, time::{Instant}};
+use std::{env, fs::File, io::{self, Write}, time::{Instant}};
 mod util;
 mod pakinterface;
-use pakinterface::{PakInterface, ResourceNode, ResourceChild};
+mod cli;
+mod dump;
+use pakinterface::{PakInterface, ResourceNode, ResourceChild, ResourceHeader, MetaData};
 
 #[allow(dead_code)]
 fn dump_csv(node: &pakinterface::ResourceNode, depth: usize) {
@@ -22,80 +24,49 @@ fn dump_csv(node: &pakinterface::ResourceNode, depth: usize) {
     }
 }
 
-#[allow(dead_code)]
 fn dump_resource_info(node: &pakinterface::ResourceNode, depth: usize) {
     for child in node.children() {
-        let extra: String;
+        let header;
         match child.contents() {
             pakinterface::ResourceType::Node(child_node) =>
             {
                 dump_resource_info(child_node, depth + 1);
-                extra = format!("{:?}", child_node.header().meta_data());
+                header = child_node.header();
             },
-            pakinterface::ResourceType::Resource(header) => 
+            pakinterface::ResourceType::Resource(resource_header) => 
             {
-                extra = format!("{:?}", header.meta_data());
+                header = resource_header;
             }
             pakinterface::ResourceType::Link(_) => {continue},
             _ => {continue},
         }
 
-        println!("{}{}, {} {}", "- ".repeat(depth), child.name(), child.offset(), extra);
+        println!("{}{}, {}", "- ".repeat(depth), child.name(), child.offset());
+        for entry in header.meta_data() {
+            println!("{}    {}", "- ".repeat(depth), entry);
+        }
     }
 }
 
-fn dump_file(path: String, child: &ResourceChild) -> io::Result<()> {
-    let mut dump_file = File::create(&path)?;
-    dump_file.write_all(&child.data()?)?;
-    Ok(())
+fn print_header(header: &ResourceHeader) {
+    println!("class {}, uuid {}, unk0 {}", header.class(), util::Guid(*header.uuid()), header.unk0());
+    for (index, entry) in header.meta_data().iter().enumerate() {
+        println!("  {}: {}", index, entry);
+    }
 }
 
-#[allow(dead_code)]
-fn dump_data(node: &pakinterface::ResourceNode, path: String) -> io::Result<()> {
-    for child in node.children() {
-        let file_name = child.name().rsplit(">\\").next().unwrap().rsplit(":").next().unwrap();
-        match child.contents() {
-            pakinterface::ResourceType::Node(child_node) =>
-            {
-                dump_data(child_node, path.clone() + file_name.to_string().split(".").next().unwrap() + "\\")?;
-            },
-            pakinterface::ResourceType::Resource(_) | pakinterface::ResourceType::Data =>
-            {
-                let file_path_string = path.clone() + file_name;
-                let file_path = std::path::Path::new(&file_path_string);
-                std::fs::create_dir_all(file_path.parent().unwrap())?;
-                dump_file(file_path_string, child)?;
-            },
-            _ => {} // ignore links
-        }
+/// Parses a metadata entry given as <kind> <type guid> <name>
+fn parse_meta_data(args: &[String]) -> Option<MetaData> {
+    match args {
+        [kind, type_guid, name] => Some(MetaData::dependency(kind.parse().ok()?, util::parse_uuid(type_guid)?, name)),
+        _ => None
     }
-    Ok(())
 }
 
-#[allow(dead_code)]
-fn replace_script(node: &mut pakinterface::ResourceNode) {
-    const FILE: &str = "ingame_cinematic_mgr.ssl";
-
-    println!("Loading replacement script from \"{}\"", FILE);
-    let mut replacement = File::open(FILE).unwrap();
-    let mut data = Vec::new();
-    replacement.read_to_end(&mut data).unwrap();
-
-    for child in node.children_mut() {
-        match child.contents_mut() {
-            pakinterface::ResourceType::Node(child_node) => {
-                replace_script(child_node);
-            },
-            pakinterface::ResourceType::Data =>
-            {
-                if child.name().contains(FILE) {
-                    println!("Replacing file \"{}\"", child.name());
-                    child.set_data(data.clone());
-                }
-            }
-            _=> {}
-        }
-    }
+fn dump_file(path: String, child: &ResourceChild) -> io::Result<()> {
+    let mut dump_file = File::create(&path)?;
+    child.with_data(|data| dump_file.write_all(data))??;
+    Ok(())
 }
 
 #[allow(dead_code)]
@@ -103,7 +74,7 @@ fn replacement_test() {
     env::set_current_dir("H:\\SteamLibrary\\steamapps\\common\\Halo The Master Chief Collection Flighting\\halo2\\preload\\paks").unwrap();
     let input_file = File::open("01b_spacestation_og.pak").unwrap();
     let mut interface = pakinterface::PakInterface::open(input_file).unwrap();
-    //replace_script(interface.get_root_node_mut());
+    //println!("{}", dump::patch_from_directory(interface.get_root_node_mut(), std::path::Path::new("patch")).unwrap());
     //interface.save(File::create("01b_spacestation_decompressed.pak").unwrap()).unwrap();
     std::process::Command::new("paktool.exe").arg("01b_spacestation_decompressed.pak").spawn().unwrap();
     std::process::exit(0);
@@ -112,6 +83,7 @@ fn replacement_test() {
 #[derive(Debug, Default)]
 struct UserInterface {
     pak_file: Option<PakInterface>,
+    pak_path: String,
     filter: String,
     node: Vec<usize>, // implemented like this cause lifetimes are hard
     exit: bool,
@@ -156,13 +128,23 @@ impl UserInterface {
     }
 
     fn open_pak(&mut self, path: &str) {
+        match pakinterface::rollback_journal(std::path::Path::new(path)) {
+            Ok(true) => println!("Rolled back an interrupted in-place save"),
+            Ok(false) => {},
+            Err(error) => println!("Failed to roll back an interrupted in-place save, {}", error)
+        }
+
         let start = Instant::now();
         match File::open(path) {
             Ok(file) => {
                 match pakinterface::PakInterface::open(file) {
                     Ok(interface) => {
+                        for diagnostic in interface.validate_loaded() {
+                            println!("{}", diagnostic);
+                        }
                         self.pak_file = Some(interface);
-                        println!("Pack file opened in {} seconds", start.elapsed().as_secs());
+                        self.pak_path = path.to_string();
+                        println!("Pack file opened in {:.3} seconds", start.elapsed().as_secs_f64());
                     }
                     Err(error) => println!("Failed to read pack file, {}", error)
                 }
@@ -175,8 +157,12 @@ impl UserInterface {
         let children = self.get_node().unwrap().children();
         for i in 0..children.len() {
             if children[i].name() == path {
-                if let pakinterface::ResourceType::Node(_) = children[i].contents() {
-                    self.node.push(i);
+                if let pakinterface::ResourceType::Node(node) = children[i].contents() {
+                    // nested nodes are read the first time they are opened
+                    match node.load() {
+                        Ok(()) => self.node.push(i),
+                        Err(error) => println!("Failed to open pack node, {}", error)
+                    }
                     return;
                 }
             }
@@ -209,6 +195,266 @@ impl UserInterface {
         }
     }
 
+    fn import(&mut self, tag: &str, path: &str) {
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(error) => {
+                println!("Failed to read file {}", error);
+                return;
+            }
+        };
+
+        if let Some(child) = self.find_child(tag) {
+            match child.contents() {
+                pakinterface::ResourceType::Data | pakinterface::ResourceType::Resource(_) => {
+                    println!("Replacing \"{}\" with {} bytes from \"{}\"", tag, data.len(), path);
+                    child.set_data(data);
+                }
+                contents => println!("Can't import into a {}", contents)
+            }
+        } else {
+            println!("No such resource!");
+        }
+    }
+
+    /// add data <name> <file>, add resource <name> <class> <file> or add node <name> <class>
+    fn add(&mut self, args: &[String]) {
+        let (contents, data) = match (args[0].as_str(), args.len()) {
+            ("data", 3) => (pakinterface::ResourceType::Data, std::fs::read(&args[2])),
+            ("resource", 4) => {
+                let header = pakinterface::ResourceHeader::new(args[2].clone(), 0, 0, Vec::new());
+                (pakinterface::ResourceType::Resource(header), std::fs::read(&args[3]))
+            },
+            ("node", 3) => {
+                let header = pakinterface::ResourceHeader::new(args[2].clone(), 0, 0, Vec::new());
+                (pakinterface::ResourceType::Node(ResourceNode::new(header)), Ok(Vec::new()))
+            },
+            _ => {
+                println!("Usage: add data <name> <file>, add resource <name> <class> <file> or add node <name> <class>");
+                return;
+            }
+        };
+
+        let data = match data {
+            Ok(data) => data,
+            Err(error) => {
+                println!("Failed to read file {}", error);
+                return;
+            }
+        };
+
+        if let Some(node) = self.get_node() {
+            match node.add_child(args[1].clone(), contents, data) {
+                Ok(_) => println!("Added \"{}\"", args[1]),
+                Err(error) => println!("Failed to add entry, {}", error)
+            }
+        } else {
+            println!("No pack file loaded!");
+        }
+    }
+
+    fn child_index(&mut self, tag: &str) -> Option<usize> {
+        self.get_node()?.children().iter().position(|child| child.name() == tag)
+    }
+
+    fn remove(&mut self, tag: &str, cascade: bool) {
+        match self.child_index(tag) {
+            Some(index) => {
+                match self.get_node().unwrap().remove_child(index, cascade) {
+                    Ok(()) => println!("Removed \"{}\"", tag),
+                    Err(error) => println!("Failed to remove entry, {}", error)
+                }
+            }
+            None => println!("No such resource!")
+        }
+    }
+
+    fn move_child(&mut self, tag: &str, to: &str) {
+        let to = match to.parse::<usize>() {
+            Ok(to) => to,
+            Err(_) => {
+                println!("Bad index \"{}\"", to);
+                return;
+            }
+        };
+        match self.child_index(tag) {
+            Some(index) => {
+                match self.get_node().unwrap().move_child(index, to) {
+                    Ok(()) => println!("Moved \"{}\" to {}", tag, to),
+                    Err(error) => println!("Failed to move entry, {}", error)
+                }
+            }
+            None => println!("No such resource!")
+        }
+    }
+
+    fn header(&mut self, args: &[String]) {
+        const USAGE: &str = "Usage: header <name> [class <class> | uuid <guid> | unk0 <value> | meta add <kind> <type guid> <name> | meta set <index> <kind> <type guid> <name> | meta rename <index> <name> | meta remove <index>]";
+        let index = match self.child_index(&args[0]) {
+            Some(index) => index,
+            None => {
+                println!("No such resource!");
+                return;
+            }
+        };
+        let child = &mut self.get_node().unwrap().children_mut()[index];
+        let meta_data_count = match child.contents() {
+            pakinterface::ResourceType::Resource(header) => header.meta_data().len(),
+            pakinterface::ResourceType::Node(node) => node.header().meta_data().len(),
+            _ => {
+                println!("\"{}\" has no header", args[0]);
+                return;
+            }
+        };
+        let meta_data_index = |value: &String| value.parse::<usize>().ok().filter(|index| *index < meta_data_count);
+
+        let edit: Box<dyn FnOnce(&mut ResourceHeader)> = match &args[1..] {
+            [] => {
+                match child.contents() {
+                    pakinterface::ResourceType::Resource(header) => print_header(header),
+                    pakinterface::ResourceType::Node(node) => print_header(node.header()),
+                    _ => {}
+                }
+                return;
+            },
+            [field, class] if field == "class" => {
+                let class = class.clone();
+                Box::new(move |header| header.set_class(class))
+            },
+            [field, uuid] if field == "uuid" => match util::parse_uuid(uuid) {
+                Some(uuid) => Box::new(move |header| header.set_uuid(uuid)),
+                None => {
+                    println!("Bad uuid \"{}\"", uuid);
+                    return;
+                }
+            },
+            [field, unk0] if field == "unk0" => match unk0.parse::<u32>() {
+                Ok(unk0) => Box::new(move |header| header.set_unk0(unk0)),
+                Err(_) => {
+                    println!("Bad value \"{}\"", unk0);
+                    return;
+                }
+            },
+            [field, action, entry @ ..] if field == "meta" && action == "add" => match parse_meta_data(entry) {
+                Some(entry) => Box::new(move |header| header.add_meta_data(entry)),
+                None => {
+                    println!("{}", USAGE);
+                    return;
+                }
+            },
+            [field, action, entry_index, entry @ ..] if field == "meta" && action == "set" => match (meta_data_index(entry_index), parse_meta_data(entry)) {
+                (Some(entry_index), Some(entry)) => Box::new(move |header| {
+                    let mut meta_data = header.meta_data().clone();
+                    meta_data[entry_index] = entry;
+                    header.set_meta_data(meta_data);
+                }),
+                _ => {
+                    println!("{}", USAGE);
+                    return;
+                }
+            },
+            [field, action, entry_index, name] if field == "meta" && action == "rename" => match meta_data_index(entry_index) {
+                Some(entry_index) => {
+                    let name = name.clone();
+                    Box::new(move |header| {
+                        let mut meta_data = header.meta_data().clone();
+                        meta_data[entry_index].rename(&name);
+                        header.set_meta_data(meta_data);
+                    })
+                },
+                None => {
+                    println!("No metadata entry \"{}\"", entry_index);
+                    return;
+                }
+            },
+            [field, action, entry_index] if field == "meta" && action == "remove" => match meta_data_index(entry_index) {
+                Some(entry_index) => Box::new(move |header| { header.remove_meta_data(entry_index); }),
+                None => {
+                    println!("No metadata entry \"{}\"", entry_index);
+                    return;
+                }
+            },
+            _ => {
+                println!("{}", USAGE);
+                return;
+            }
+        };
+
+        match child.edit_header(edit) {
+            Ok(()) => println!("Changed the header of \"{}\"", args[0]),
+            Err(error) => println!("Failed to change the header, {}", error)
+        }
+    }
+
+    fn find_uuid(&mut self, uuid: &str) {
+        let uuid = match util::parse_uuid(uuid) {
+            Some(uuid) => uuid,
+            None => {
+                println!("Bad uuid \"{}\"", uuid);
+                return;
+            }
+        };
+        if let Some(pak) = &self.pak_file {
+            let matches = pak.find_by_uuid(uuid);
+            cli::print_uuid_matches(&matches);
+            println!("{} entries with uuid {}", matches.len(), util::Guid(uuid));
+        } else {
+            println!("No pack file loaded!");
+        }
+    }
+
+    fn save(&mut self, path: &str, compression_level: Option<u32>) {
+        if let Some(pak) = &mut self.pak_file {
+            // saving over the open pack file keeps the old one around
+            let keep_backup = cli::is_same_file(&self.pak_path, path);
+            match pak.save_as(std::path::Path::new(path), compression_level, keep_backup) {
+                Ok(backup) => {
+                    println!("Pack file saved as \"{}\"", path);
+                    if let Some(backup) = backup {
+                        println!("Kept the previous version as \"{}\"", backup.display());
+                    }
+                    self.pak_path = path.to_string();
+                },
+                Err(error) => println!("Failed to save pack file, {}", error)
+            }
+        } else {
+            println!("No pack file loaded!");
+        }
+    }
+
+    fn extract_all(&mut self, path: &str) {
+        if let Some(node) = self.get_node() {
+            match dump::extract_all(node, std::path::Path::new(path)) {
+                Ok(_) => println!("Extracted to \"{}\"", path),
+                Err(error) => println!("Failed to extract, {}", error)
+            }
+        } else {
+            println!("No pack file loaded!");
+        }
+    }
+
+    fn patch(&mut self, path: &str) {
+        if let Some(node) = self.get_node() {
+            match dump::patch_from_directory(node, std::path::Path::new(path)) {
+                Ok(report) => println!("{}", report),
+                Err(error) => println!("Failed to patch from directory, {}", error)
+            }
+        } else {
+            println!("No pack file loaded!");
+        }
+    }
+
+    fn save_in_place(&mut self) {
+        if let Some(pak) = &mut self.pak_file {
+            match pak.save_in_place(std::path::Path::new(&self.pak_path)) {
+                Ok(()) => println!("Pack file saved in place"),
+                Err(error) => println!("Failed to save pack file in place, {}", error)
+            }
+        } else {
+            println!("No pack file loaded!");
+        }
+    }
+
     fn run(&mut self) -> io::Result<()> {
         while !self.exit {
             if self.filter.is_empty() {
@@ -243,6 +489,77 @@ impl UserInterface {
                             println!("Export takes 1 arg");
                         }
                     },
+                    "import" => {
+                        if input.len() == 3 {
+                            self.import(input[1].as_str(), input[2].as_str());
+                        } else {
+                            println!("Import takes 2 args");
+                        }
+                    },
+                    "extract-all" => {
+                        if input.len() == 2 {
+                            self.extract_all(input[1].as_str());
+                        } else {
+                            println!("Extract-all takes 1 arg");
+                        }
+                    },
+                    "patch" => {
+                        if input.len() == 2 {
+                            self.patch(input[1].as_str());
+                        } else {
+                            println!("Patch takes 1 arg");
+                        }
+                    },
+                    "add" => {
+                        if input.len() >= 4 {
+                            self.add(&input[1..]);
+                        } else {
+                            println!("Add takes at least 3 args");
+                        }
+                    },
+                    "remove" => {
+                        if input.len() == 2 {
+                            self.remove(input[1].as_str(), false);
+                        } else if input.len() == 3 && input[2] == "--cascade" {
+                            self.remove(input[1].as_str(), true);
+                        } else {
+                            println!("Remove takes 1 arg and optionally --cascade");
+                        }
+                    },
+                    "move" => {
+                        if input.len() == 3 {
+                            self.move_child(input[1].as_str(), input[2].as_str());
+                        } else {
+                            println!("Move takes 2 args");
+                        }
+                    },
+                    "save" => {
+                        if input.len() == 2 {
+                            self.save(input[1].as_str(), None);
+                        } else if input.len() == 3 {
+                            match input[2].parse::<u32>() {
+                                Ok(level) if level <= 9 => self.save(input[1].as_str(), Some(level)),
+                                _ => println!("Compression level must be 0-9")
+                            }
+                        } else {
+                            println!("Save takes 1 or 2 args");
+                        }
+                    },
+                    "header" => {
+                        if input.len() >= 2 {
+                            self.header(&input[1..]);
+                        } else {
+                            println!("Header takes at least 1 arg");
+                        }
+                    },
+                    "find-uuid" => {
+                        if input.len() == 2 {
+                            self.find_uuid(input[1].as_str());
+                        } else {
+                            println!("Find-uuid takes 1 arg");
+                        }
+                    },
+                    "save-in-place" => self.save_in_place(),
                     "list" => self.list(),
                     "close" => self.close(),
                     "exit" | "quit" => self.exit = true,
@@ -253,6 +570,13 @@ impl UserInterface {
                             println!("No pack file loaded!");
                         }
                     }
+                    "print_info" => {
+                        if let Some(node) = self.get_node() {
+                            dump_resource_info(node, 0);
+                        } else {
+                            println!("No pack file loaded!");
+                        }
+                    }
                     "filter" => {
                         if input.len() == 2 {
                             self.filter = input[1].clone();
@@ -279,8 +603,13 @@ impl UserInterface {
 }
 
 fn main() -> io::Result<()> {
-    let mut interface = UserInterface::default();
     let args: Vec<String> = env::args().collect();
+    // more than a pak to open has to be a command, run reports the unknown ones
+    if args.len() > 2 || (args.len() > 1 && cli::is_command(args[1].as_str())) {
+        std::process::exit(cli::run(&args[1..]));
+    }
+
+    let mut interface = UserInterface::default();
     if args.len() == 2 {
         interface.open_pak(args[1].as_str());
     }
@@ -0,0 +1,69 @@
+use std::io;
+use super::{ResourceNode, ResourceChild, ResourceHeader, ResourceType, NODE_CLASSES, check_new_child};
+
+/// Builds a node from scratch, children are written in the order they are added
+#[derive(Debug)]
+pub struct NodeBuilder {
+    header: ResourceHeader,
+    children: Vec<ResourceChild>
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl NodeBuilder {
+    pub fn new(header: ResourceHeader) -> Self {
+        NodeBuilder { header: header, children: Vec::new() }
+    }
+
+    fn push(&mut self, name: String, contents: ResourceType, data: Option<Vec<u8>>) -> usize {
+        self.children.push(ResourceChild::new_entry(name, contents, data));
+        self.children.len() - 1
+    }
+
+    /// Adds raw data, returns the index of the new child
+    pub fn add_data(&mut self, name: String, data: Vec<u8>) -> usize {
+        self.push(name, ResourceType::Data, Some(data))
+    }
+
+    /// Adds data with a resource header, returns the index of the new child
+    pub fn add_resource(&mut self, name: String, header: ResourceHeader, data: Vec<u8>) -> usize {
+        self.push(name, ResourceType::Resource(header), Some(data))
+    }
+
+    /// Adds a nested node, returns the index of the new child
+    pub fn add_node(&mut self, name: String, node: NodeBuilder) -> io::Result<usize> {
+        let node = node.build()?;
+        Ok(self.push(name, ResourceType::Node(node), None))
+    }
+
+    /// Adds a link to a child of this node that isn't a link itself, it may be added later
+    pub fn add_link(&mut self, name: String, target: usize) -> usize {
+        self.push(name, ResourceType::Link(target), None)
+    }
+
+    /// Checks the classes and links are valid
+    pub fn build(mut self) -> io::Result<ResourceNode> {
+        if !NODE_CLASSES.contains(&&self.header.class[..]) {
+            return Err(invalid_data(format!("\"{}\" isn't a node class", self.header.class)));
+        }
+
+        for child_index in 0..self.children.len() {
+            let child = &self.children[child_index];
+            check_new_child(&child.name, &child.contents)?;
+            if let ResourceType::Link(target) = &child.contents {
+                let target = *target;
+                let target_size = match self.children.get(target).map(|target_child| &target_child.contents) {
+                    Some(ResourceType::Link(_)) | None => {
+                        return Err(invalid_data(format!("\"{}\" links to invalid child {}", child.name, target)));
+                    },
+                    Some(_) => self.children[target].size
+                };
+                self.children[child_index].size = target_size;
+                self.children[target].has_active_links = true;
+            }
+        }
+        Ok(ResourceNode::with_children(self.header, self.children))
+    }
+}
@@ -0,0 +1,85 @@
+use std::{fmt, io};
+
+/// Why reading or writing a pak failed, offsets are positions in the (decompressed) pak
+#[derive(Debug)]
+pub enum PakError {
+    Io(io::Error),
+    /// The file doesn't start with a resource header
+    BadMagic { found: u32 },
+    /// Expected a 0x01 delimiter between the sections of a node
+    BadDelimiter { offset: u64, found: u8 },
+    /// A field with a fixed value in every pak we know of has another value
+    UnexpectedVersion { offset: u64, field: &'static str, expected: u32, found: u32 },
+    /// The header size doesn't match the size of the metadata read
+    BadHeaderSize { offset: u64, expected: u64, found: u64 },
+    /// Resource class that isn't UTF-8 or doesn't fit in the header
+    InvalidClass { offset: u64, class: String },
+    /// A child points past the end of the file, offset is the position of its data
+    ChildOutOfRange { name: String, offset: u64, size: u32 },
+    /// The operation doesn't apply to this kind of child
+    WrongContentType { name: String, expected: &'static str, found: String }
+}
+
+pub type PakResult<T> = Result<T, PakError>;
+
+impl fmt::Display for PakError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PakError::Io(error) => write!(f, "{}", error),
+            PakError::BadMagic { found } => write!(f, "Not a pak, bad magic {:#010x}", found),
+            PakError::BadDelimiter { offset, found } => write!(f, "Bad stream delimiter {:#04x} at offset {:#x}", found, offset),
+            PakError::UnexpectedVersion { offset, field, expected, found } =>
+                write!(f, "Unexpected {} {:#x} at offset {:#x}, expected {:#x}", field, found, offset, expected),
+            PakError::BadHeaderSize { offset, expected, found } =>
+                write!(f, "Header at offset {:#x} is {:#x} bytes long but its size field says {:#x}", offset, found, expected),
+            PakError::InvalidClass { offset, class } => write!(f, "Invalid class \"{}\" in header at offset {:#x}", class, offset),
+            PakError::ChildOutOfRange { name, offset, size } =>
+                write!(f, "\"{}\" ({:#x} bytes at offset {:#x}) runs past the end of the file", name, size, offset),
+            PakError::WrongContentType { name, expected, found } => write!(f, "\"{}\" is {}, expected {}", name, found, expected)
+        }
+    }
+}
+
+impl std::error::Error for PakError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PakError::Io(error) => Some(error),
+            _ => None
+        }
+    }
+}
+
+/// Io errors only keep their kind and message
+impl Clone for PakError {
+    fn clone(&self) -> Self {
+        match self {
+            PakError::Io(error) => PakError::Io(io::Error::new(error.kind(), error.to_string())),
+            PakError::BadMagic { found } => PakError::BadMagic { found: *found },
+            PakError::BadDelimiter { offset, found } => PakError::BadDelimiter { offset: *offset, found: *found },
+            PakError::UnexpectedVersion { offset, field, expected, found } =>
+                PakError::UnexpectedVersion { offset: *offset, field: field, expected: *expected, found: *found },
+            PakError::BadHeaderSize { offset, expected, found } => PakError::BadHeaderSize { offset: *offset, expected: *expected, found: *found },
+            PakError::InvalidClass { offset, class } => PakError::InvalidClass { offset: *offset, class: class.clone() },
+            PakError::ChildOutOfRange { name, offset, size } =>
+                PakError::ChildOutOfRange { name: name.clone(), offset: *offset, size: *size },
+            PakError::WrongContentType { name, expected, found } => PakError::WrongContentType { name: name.clone(), expected: expected, found: found.clone() }
+        }
+    }
+}
+
+impl From<io::Error> for PakError {
+    fn from(error: io::Error) -> Self {
+        PakError::Io(error)
+    }
+}
+
+/// Lets code working with io::Result use `?` on pak operations
+impl From<PakError> for io::Error {
+    fn from(error: PakError) -> Self {
+        match error {
+            PakError::Io(error) => error,
+            PakError::WrongContentType { .. } => io::Error::new(io::ErrorKind::InvalidInput, error),
+            error => io::Error::new(io::ErrorKind::InvalidData, error)
+        }
+    }
+}
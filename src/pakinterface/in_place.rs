@@ -0,0 +1,276 @@
+use std::{borrow::Cow, fs::{File, OpenOptions}, io, path::{Path, PathBuf}};
+use crate::util::{self, BinaryStream};
+use super::{PakInterface, PakResult, ResourceNode, ResourceChild, ResourceType, NodeModifiedState, read_input, write_input};
+use super::pak_io::{InputFile, UncompressedFile};
+
+/// Marks the start and the end of a complete journal
+const JOURNAL_MAGIC: u32 = util::u32_from_str("JRNL");
+
+/// Path of the journal kept next to a pak while it is patched in place
+pub(super) fn journal_path(path: &Path) -> PathBuf {
+    let mut journal_path = path.as_os_str().to_owned();
+    journal_path.push(".journal");
+    PathBuf::from(journal_path)
+}
+
+fn needs_full_save(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("{}, the pak needs a full save", reason))
+}
+
+/// A child whose data is rewritten in the existing file
+struct Edit {
+    name: String,
+    /// child indices from the root node down
+    path: Vec<usize>,
+    node_base: u64,
+    /// positions of the table entries of the child and the links to it
+    offset_fields: Vec<u64>,
+    size_fields: Vec<u64>,
+    /// where the new data goes
+    position: u64,
+    size: u32
+}
+
+fn collect_edits(node: &ResourceNode, path: &mut Vec<usize>, edits: &mut Vec<Edit>) -> io::Result<()> {
+    if node.layout_changed {
+        return Err(needs_full_save("Children were removed or moved"));
+    }
+    if node.header.dirty {
+        return Err(needs_full_save("A node header was changed"));
+    }
+    // nodes that were never read haven't changed
+    let tables = match node.tables.get() {
+        Some(Ok(tables)) => tables,
+        _ => return Ok(())
+    };
+
+    let child_count = tables.children.len() as u64;
+    let size_table = tables.table_offset + child_count * 8 + 1;
+    for (index, child) in tables.children.iter().enumerate() {
+        if child.is_new_entry || child.meta_data_dirty {
+            return Err(needs_full_save(&format!("\"{}\" was added or renamed", child.name)));
+        }
+
+        path.push(index);
+        match &child.contents {
+            ResourceType::Node(child_node) => collect_edits(child_node, path, edits)?,
+            ResourceType::Data | ResourceType::Resource(_) if child.new_data.is_some() || child.header_changed() => {
+                let mut entries = vec![index];
+                entries.extend(node.links_to(index));
+                edits.push(Edit {
+                    name: child.name.clone(),
+                    path: path.clone(),
+                    node_base: child.node_base,
+                    offset_fields: entries.iter().map(|entry| tables.table_offset + *entry as u64 * 8).collect(),
+                    size_fields: entries.iter().map(|entry| size_table + *entry as u64 * 4).collect(),
+                    position: 0,
+                    size: child.written_size()
+                });
+            },
+            _ => {}
+        }
+        path.pop();
+    }
+    Ok(())
+}
+
+fn child_at<'a>(node: &'a ResourceNode, path: &[usize]) -> &'a ResourceChild {
+    let child = &node.children()[path[0]];
+    match &child.contents {
+        ResourceType::Node(child_node) if path.len() > 1 => child_at(child_node, &path[1..]),
+        _ => child
+    }
+}
+
+fn node_at_mut<'a>(node: &'a mut ResourceNode, path: &[usize]) -> &'a mut ResourceNode {
+    if path.is_empty() {
+        return node;
+    }
+    match node.children_mut()[path[0]].contents_mut() {
+        ResourceType::Node(child_node) => node_at_mut(child_node, &path[1..]),
+        _ => panic!("internal error")
+    }
+}
+
+/// Marks the nodes on `path` as matching the file again once their edits are written
+fn mark_clean(node: &mut ResourceNode, path: &[usize]) {
+    node.state = NodeModifiedState::Clean;
+    if let Some((index, rest)) = path.split_first() {
+        if let ResourceType::Node(child_node) = node.children_mut()[*index].contents_mut() {
+            mark_clean(child_node, rest);
+        }
+    }
+}
+
+/// Saves the current contents of `ranges` and the length of `file` so `rollback_journal` can
+/// undo whatever is written to them next
+pub(super) fn write_journal(file: &mut UncompressedFile, journal_path: &Path, ranges: &[(u64, u64)]) -> io::Result<()> {
+    let mut journal = UncompressedFile::new(File::create(journal_path)?);
+    journal.write_u32(JOURNAL_MAGIC)?;
+    journal.write_u64(file.length()?)?;
+    journal.write_u32(ranges.len() as u32)?;
+    for (position, length) in ranges {
+        journal.write_u64(*position)?;
+        journal.write_u64(*length)?;
+        file.seek(*position)?;
+        file.copy_data(&mut journal, *length as usize)?;
+    }
+    journal.write_u32(JOURNAL_MAGIC)?;
+    journal.sync()
+}
+
+/// Undoes an in-place save that was interrupted, returns true if there was one to undo
+pub fn rollback_journal(path: &Path) -> io::Result<bool> {
+    let journal_path = journal_path(path);
+    let mut journal = match File::open(&journal_path) {
+        Ok(file) => UncompressedFile::new(file),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(error) => return Err(error)
+    };
+
+    // the pak isn't touched until the whole journal is on disk, so an incomplete one can be dropped
+    let mut read_journal = || -> io::Result<Option<(u64, Vec<(u64, Vec<u8>)>)>> {
+        if journal.length()? < 16 || journal.read_u32()? != JOURNAL_MAGIC {
+            return Ok(None);
+        }
+        let original_length = journal.read_u64()?;
+        let range_count = journal.read_u32()?;
+        let mut ranges = Vec::new();
+        for _ in 0..range_count {
+            let position = journal.read_u64()?;
+            let length = journal.read_u64()?;
+            ranges.push((position, journal.read_vector(length as usize)?));
+        }
+        if journal.read_u32()? != JOURNAL_MAGIC {
+            return Ok(None);
+        }
+        Ok(Some((original_length, ranges)))
+    };
+
+    let journal_contents = match read_journal() {
+        Ok(contents) => contents,
+        Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => None,
+        Err(error) => return Err(error)
+    };
+
+    let rolled_back = journal_contents.is_some();
+    if let Some((original_length, ranges)) = journal_contents {
+        let mut file = UncompressedFile::new(OpenOptions::new().read(true).write(true).open(path)?);
+        for (position, data) in ranges {
+            file.seek(position)?;
+            file.write_bytes(&data)?;
+        }
+        file.set_length(original_length)?;
+        file.sync()?;
+    }
+
+    std::fs::remove_file(&journal_path)?;
+    Ok(rolled_back)
+}
+
+impl PakInterface {
+    /// Writes modified entries straight into the pak at `path`, which has to be the file the pak
+    /// was opened from. Entries are rewritten where they are if they fit, entries of the root node
+    /// that grew are moved to the end of the file. Only data changes can be saved this way.
+    pub fn save_in_place(&mut self, path: &Path) -> PakResult<()> {
+        match &*read_input(self.input()?) {
+            InputFile::Compressed(_) => return Err(needs_full_save("Compressed paks can't be patched in place").into()),
+            InputFile::Memory(_) => return Err(needs_full_save("Paks opened from memory can't be patched in place").into()),
+            _ => {}
+        }
+        if journal_path(path).exists() {
+            return Err(io::Error::new(io::ErrorKind::Other, "An earlier in-place save was interrupted, reopen the pak to roll it back").into());
+        }
+
+        let mut edits = Vec::new();
+        collect_edits(&self.root_node, &mut Vec::new(), &mut edits)?;
+        if edits.is_empty() {
+            return Ok(());
+        }
+
+        // the map would miss whatever gets appended, it is mapped again once the file is patched
+        write_input(self.input()?).unmap()?;
+        let mut file = UncompressedFile::new(OpenOptions::new().read(true).write(true).open(path)?);
+        let original_length = file.length()?;
+        if original_length != read_input(self.input()?).length()? {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Not the file the pak was opened from").into());
+        }
+
+        // work out where everything goes before touching the file
+        let mut end = original_length;
+        for edit in &mut edits {
+            file.seek(edit.offset_fields[0])?;
+            let offset = file.read_u64()?;
+            file.seek(edit.size_fields[0])?;
+            let capacity = file.read_u32()?;
+
+            if edit.size <= capacity {
+                edit.position = edit.node_base + offset;
+            } else if edit.path.len() == 1 {
+                edit.position = end;
+                end += edit.size as u64;
+            } else {
+                return Err(needs_full_save(&format!("\"{}\" no longer fits in its node", edit.name)).into());
+            }
+        }
+
+        let mut ranges = Vec::new();
+        for edit in &edits {
+            if edit.position < original_length {
+                ranges.push((edit.position, edit.size as u64));
+            }
+            ranges.extend(edit.offset_fields.iter().map(|field| (*field, 8)));
+            ranges.extend(edit.size_fields.iter().map(|field| (*field, 4)));
+        }
+        let journal_path = journal_path(path);
+        write_journal(&mut file, &journal_path, &ranges)?;
+
+        for edit in &edits {
+            let child = child_at(&self.root_node, &edit.path);
+            // read before a longer header overwrites the start of the old data
+            let data = match &child.new_data {
+                Some(data) => Cow::Borrowed(data),
+                None => Cow::Owned(child.data()?)
+            };
+            file.seek(edit.position)?;
+            if let ResourceType::Resource(header) = &child.contents {
+                header.write(&mut file)?;
+            }
+            file.write_bytes(&data)?;
+
+            for field in &edit.offset_fields {
+                file.seek(*field)?;
+                file.write_u64(edit.position - edit.node_base)?;
+            }
+            for field in &edit.size_fields {
+                file.seek(*field)?;
+                file.write_u32(edit.size)?;
+            }
+        }
+        file.sync()?;
+        std::fs::remove_file(&journal_path)?;
+
+        // the tree now describes the patched file
+        for edit in &edits {
+            let (index, node_path) = edit.path.split_last().unwrap();
+            let node = node_at_mut(&mut self.root_node, node_path);
+            let mut entries = vec![*index];
+            entries.extend(node.links_to(*index));
+            for entry in entries {
+                let child = &mut node.children_mut()[entry];
+                child.offset = edit.position - edit.node_base;
+                child.size = edit.size;
+            }
+            let child = &mut node.children_mut()[*index];
+            child.new_data = None;
+            if let ResourceType::Resource(header) = &mut child.contents {
+                header.dirty = false;
+                child.header_len = header.size;
+            }
+            mark_clean(&mut self.root_node, node_path);
+        }
+        self.root_node.tables_mut()?.data_len += end - original_length;
+        write_input(self.input()?).map();
+        Ok(())
+    }
+}
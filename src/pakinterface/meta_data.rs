@@ -0,0 +1,94 @@
+use std::fmt;
+use crate::util::Guid;
+use super::MetaData;
+
+/// Decoded `name_data` of a metadata entry. Resources list the names of the resources they
+/// depend on here, usually as a plain string and sometimes with a terminating nul.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetaDataName {
+    Text { name: String, nul_terminated: bool },
+    /// Anything that isn't printable text is kept as is
+    Raw(Vec<u8>)
+}
+
+impl MetaDataName {
+    pub fn decode(data: &[u8]) -> Self {
+        let (text, nul_terminated) = match data.split_last() {
+            Some((0, text)) => (text, true),
+            _ => (data, false)
+        };
+        match std::str::from_utf8(text) {
+            Ok(name) if !name.chars().any(char::is_control) => MetaDataName::Text { name: name.to_string(), nul_terminated: nul_terminated },
+            _ => MetaDataName::Raw(data.to_vec())
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            MetaDataName::Text { name, nul_terminated } => {
+                let mut data = name.as_bytes().to_vec();
+                if *nul_terminated {
+                    data.push(0);
+                }
+                data
+            },
+            MetaDataName::Raw(data) => data.clone()
+        }
+    }
+}
+
+impl fmt::Display for MetaDataName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MetaDataName::Text { name, .. } => write!(f, "\"{}\"", name),
+            MetaDataName::Raw(data) => {
+                write!(f, "{} bytes ", data.len())?;
+                for byte in data {
+                    write!(f, "{:02x}", byte)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Nothing documents the format, the names here are our reading of it rather than known
+/// semantics. The name is the one part seen holding text, the names of other resources, so
+/// entries are treated as dependencies. `kind` and `type_guid` are only names for `unk0` and
+/// `unk1`, picked from their sizes and the second one looking like a GUID, which is why the
+/// raw fields keep their `unk` names.
+impl MetaData {
+    /// Entry describing a dependency on the resource called `name`
+    pub fn dependency(kind: u32, type_guid: u128, name: &str) -> Self {
+        MetaData::new(kind, type_guid, MetaDataName::Text { name: name.to_string(), nul_terminated: false }.encode())
+    }
+
+    /// `unk0`, see above
+    pub fn kind(&self) -> u32 {
+        self.unk0
+    }
+
+    /// `unk1`, see above
+    pub fn type_guid(&self) -> u128 {
+        self.unk1
+    }
+
+    pub fn name(&self) -> MetaDataName {
+        MetaDataName::decode(&self.name_data)
+    }
+
+    /// Changes the name to `name` as text, keeping the terminating nul if the old name had one
+    pub fn rename(&mut self, name: &str) {
+        let nul_terminated = match self.name() {
+            MetaDataName::Text { nul_terminated, .. } => nul_terminated,
+            MetaDataName::Raw(_) => false
+        };
+        self.name_data = MetaDataName::Text { name: name.to_string(), nul_terminated: nul_terminated }.encode();
+    }
+}
+
+impl fmt::Display for MetaData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "kind {}, type {}, {}", self.kind(), Guid(self.type_guid()), self.name())
+    }
+}
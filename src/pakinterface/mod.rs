@@ -1,13 +1,44 @@
-use std::{fs::File, io, rc::{Weak, Rc}, cell::{RefCell}};
+use std::{collections::HashMap, convert::TryFrom, fs::File, io, sync::{Arc, OnceLock, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard, Weak}};
 use getset::{Getters};
 use crate::util;
 use util::BinaryStream;
+mod error;
+pub use error::{PakError, PakResult};
+mod meta_data;
+pub use meta_data::MetaDataName;
 mod pak_io;
-use pak_io::UncompressedFile;
+pub use pak_io::MemoryStream;
+use pak_io::{UncompressedFile, CompressedWriter, InputFile, InputCursor};
+mod builder;
+pub use builder::NodeBuilder;
+mod in_place;
+pub use in_place::rollback_journal;
+mod save_as;
+mod validate;
+mod uuid_index;
+pub use uuid_index::UuidMatch;
+#[cfg(test)]
+mod tests;
+pub use validate::Severity;
 
 pub const RESOURCE_MAGIC: u32 = util::u32_from_str("RES1");
+/// Size of a resource header without any metadata
+pub const RESOURCE_HEADER_SIZE: u64 = 0x40;
 pub const NODE_CLASSES: [&str; 3] = ["pak", "ssl_block", "cache_block"];
 
+/// Input file shared by a pak and everything read from it, it is only locked for writing
+/// to switch between mapped and plain reads
+type SharedInput = RwLock<InputFile>;
+
+/// A panic can't leave the input file half changed, so poisoning is ignored
+fn read_input(input: &SharedInput) -> RwLockReadGuard<'_, InputFile> {
+    input.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn write_input(input: &SharedInput) -> RwLockWriteGuard<'_, InputFile> {
+    input.write().unwrap_or_else(PoisonError::into_inner)
+}
+
 
 #[derive(Debug)]
 pub enum ResourceType {
@@ -38,7 +69,7 @@ impl std::fmt::Display for ResourceType {
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, PartialOrd)]
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 enum NodeModifiedState {
     /// No change
     Clean,
@@ -81,12 +112,33 @@ pub struct ResourceChild {
     /// are there active links to this node?
     has_active_links: bool,
     is_new_entry: bool,
-
-    input_file: Weak<std::cell::RefCell<UncompressedFile>>
+    /// flagged as a link in the input file but no child starts at its offset, read as Data
+    dangling_link: bool,
+    /// runs past the end of the input file, its data can't be read
+    out_of_range: bool,
+    /// length of the resource header in the input file, the data follows it
+    header_len: u32,
+
+    input_file: Weak<SharedInput>
 }
 
 #[allow(dead_code)]
 impl ResourceChild {
+    /// Creates a child that isn't in the input file yet
+    fn new_entry(name: String, contents: ResourceType, data: Option<Vec<u8>>) -> Self {
+        let mut child = ResourceChild::default();
+        child.size = match (&contents, &data) {
+            (ResourceType::Resource(header), Some(data)) => header.size + data.len() as u32,
+            (_, Some(data)) => data.len() as u32,
+            _ => 0
+        };
+        child.name = name;
+        child.contents = contents;
+        child.new_data = data;
+        child.is_new_entry = true;
+        child
+    }
+
     pub fn contents_mut(&mut self) -> &mut ResourceType {
         &mut self.contents
     }
@@ -97,38 +149,102 @@ impl ResourceChild {
         self.meta_data_dirty = true;
     }
 
-    /// Get raw data
-    /// Will panic if not Data or Resource
-    pub fn data(&self) -> io::Result<Vec<u8>> {
+    /// Get raw data, only Data and Resource children have any
+    pub fn data(&self) -> PakResult<Vec<u8>> {
+        self.with_data(|data| data.to_vec())
+    }
+
+    /// Passes the raw data to `f`, borrowing it straight from the pak when it is memory mapped
+    pub fn with_data<R, F: FnOnce(&[u8]) -> R>(&self, f: F) -> PakResult<R> {
         let read_offset;
         match &self.contents {
             ResourceType::Data => read_offset = 0,
-            ResourceType::Resource(header) => read_offset = header.size,
-            _=> panic!("Bad contents")
+            ResourceType::Resource(_) => read_offset = self.header_len,
+            contents => return Err(PakError::WrongContentType { name: self.name.clone(), expected: "Raw Data or Resource Data", found: contents.to_string() })
         };
         
         match &self.new_data {
-            Some(data) => Ok(data.to_vec()),
+            Some(data) => Ok(f(data)),
+            None if self.out_of_range => Err(PakError::ChildOutOfRange { name: self.name.clone(), offset: self.node_base.saturating_add(self.offset), size: self.size }),
             None => {
-                let mut data = vec![0u8; (self.size - read_offset) as usize];
-                // value is still valid if we exist
-                let file_ref = self.input_file.upgrade().unwrap();
-                let mut file = file_ref.borrow_mut();
-                file.seek(self.offset + self.node_base + read_offset as u64)?;
-                file.read_bytes(&mut data)?;
-                Ok(data)
+                let size = self.size.saturating_sub(read_offset) as usize;
+                let position = self.offset + self.node_base + read_offset as u64;
+                let file_ref = self.input_file.upgrade().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "The pak was closed"))?;
+                let file = read_input(&file_ref);
+                if let Some(data) = file.mapped_slice(position, size) {
+                    return Ok(f(data?));
+                }
+
+                let mut data = vec![0u8; size];
+                file.read_at(position, &mut data)?;
+                drop(file);
+                Ok(f(&data))
             }
         }
     }
 
-    /// Set raw data
+    /// Set raw data, for resources this is the data following the header
     pub fn set_data(&mut self, data: Vec<u8>) {
-        self.size = data.len() as u32;
+        self.size = match &self.contents {
+            ResourceType::Resource(header) => header.size + data.len() as u32,
+            _ => data.len() as u32
+        };
         self.new_data = Some(data);
     }
+
+    /// A resource header was changed but the data following it is still the one in the input file
+    fn header_changed(&self) -> bool {
+        self.new_data.is_none() && matches!(&self.contents, ResourceType::Resource(header) if header.dirty)
+    }
+
+    /// Size of the child once it is written
+    fn written_size(&self) -> u32 {
+        match &self.contents {
+            ResourceType::Resource(header) if self.header_changed() => header.size + self.size.saturating_sub(self.header_len),
+            _ => self.size
+        }
+    }
+
+    /// Changes the header of a Resource or Node child, the edit is undone if the class
+    /// is invalid or would turn one into the other
+    pub fn edit_header<F: FnOnce(&mut ResourceHeader)>(&mut self, edit: F) -> PakResult<()> {
+        // the header can change length, so the data is written out again after it
+        let data = match (&self.contents, &self.new_data) {
+            (ResourceType::Resource(_), None) => Some(self.data()?),
+            _ => None
+        };
+
+        let header = match &mut self.contents {
+            ResourceType::Resource(header) => header,
+            ResourceType::Node(node) => &mut node.header,
+            contents => return Err(PakError::WrongContentType { name: self.name.clone(), expected: "Resource Data or Node", found: contents.to_string() })
+        };
+        let old_header = header.clone();
+        edit(header);
+        if let Err(error) = check_new_child(&self.name, &self.contents) {
+            match &mut self.contents {
+                ResourceType::Resource(header) => *header = old_header,
+                ResourceType::Node(node) => node.header = old_header,
+                _ => {}
+            }
+            return Err(error.into());
+        }
+
+        match &mut self.contents {
+            ResourceType::Resource(_) => {
+                if let Some(data) = data {
+                    self.new_data = Some(data);
+                }
+                let data = self.new_data.take().unwrap_or_default();
+                self.set_data(data);
+            },
+            _ => {}
+        }
+        Ok(())
+    }
 }
 
-#[derive(Debug, Default, Getters)]
+#[derive(Debug, Default, Clone, Getters)]
 #[getset(get = "pub")]
 pub struct MetaData {
     unk0: u32,
@@ -136,7 +252,7 @@ pub struct MetaData {
     name_data: Vec<u8>
 }
 
-#[derive(Debug, Default, Getters)]
+#[derive(Debug, Default, Clone, Getters)]
 #[getset(get = "pub")]
 pub struct ResourceHeader {
     /* Read from pak */
@@ -149,13 +265,75 @@ pub struct ResourceHeader {
     /* Implementation detail */
     #[getset(get)]
     base: u64,
-    size: u32
+    size: u32,
+    /// changed since it was read, the child has to be written again
+    #[getset(skip)]
+    dirty: bool
+}
+
+impl MetaData {
+    pub fn new(unk0: u32, unk1: u128, name_data: Vec<u8>) -> Self {
+        MetaData { unk0: unk0, unk1: unk1, name_data: name_data }
+    }
+
+    /// Size of the entry in the header, the name data is prefixed by its length
+    fn size(&self) -> u32 {
+        4 + 16 + 4 + self.name_data.len() as u32
+    }
 }
 
+#[allow(dead_code)]
 impl ResourceHeader {
+    pub fn new(class: String, uuid: u128, unk0: u32, meta_data: Vec<MetaData>) -> Self {
+        let mut header = ResourceHeader { class: class, uuid: uuid, unk0: unk0, meta_data: meta_data, base: 0, size: 0, dirty: false };
+        header.update_size();
+        header
+    }
+
+    fn update_size(&mut self) {
+        self.size = RESOURCE_HEADER_SIZE as u32 + self.meta_data.iter().map(MetaData::size).sum::<u32>();
+        self.dirty = true;
+    }
+
+    /// Headers of children should be changed through `ResourceChild::edit_header`, which
+    /// checks the new class
+    pub fn set_class(&mut self, class: String) {
+        self.class = class;
+        self.dirty = true;
+    }
+
+    pub fn set_uuid(&mut self, uuid: u128) {
+        self.uuid = uuid;
+        self.dirty = true;
+    }
+
+    pub fn set_unk0(&mut self, unk0: u32) {
+        self.unk0 = unk0;
+        self.dirty = true;
+    }
+
+    pub fn set_meta_data(&mut self, meta_data: Vec<MetaData>) {
+        self.meta_data = meta_data;
+        self.update_size();
+    }
+
+    pub fn add_meta_data(&mut self, entry: MetaData) {
+        self.meta_data.push(entry);
+        self.update_size();
+    }
+
+    pub fn remove_meta_data(&mut self, index: usize) -> Option<MetaData> {
+        if index >= self.meta_data.len() {
+            return None;
+        }
+        let entry = self.meta_data.remove(index);
+        self.update_size();
+        Some(entry)
+    }
+
     /// Returns the header if a valid one exists with the file pointer pointing 
     /// just beyond the end of the header, on error file pointer is undefined
-    pub fn read<T: BinaryStream>(file : &mut T) -> io::Result<Option<ResourceHeader>> {
+    pub fn read<T: BinaryStream>(file : &mut T) -> PakResult<Option<ResourceHeader>> {
         let magic = file.read_u32()?;
         if magic != RESOURCE_MAGIC {
             return Ok(None);
@@ -167,39 +345,53 @@ impl ResourceHeader {
         let mut resource_class = [0u8; 0x20];
         file.read_bytes(&mut resource_class)?;
         let len = util::string_length(&resource_class);
-        header.class = String::from_utf8(resource_class[..len].to_vec()).unwrap();
+        header.class = String::from_utf8(resource_class[..len].to_vec())
+            .map_err(|_| PakError::InvalidClass { offset: header.base + 4, class: String::from_utf8_lossy(&resource_class[..len]).into_owned() })?;
 
         header.uuid = file.read_u128()?;
         header.unk0 = file.read_u32()?;
 
         let meta_data_count = file.read_u32()?;
-        header.size = file.read_u32()? + 0x40;
+        let stored_size = file.read_u32()?;
+        header.size = stored_size.checked_add(RESOURCE_HEADER_SIZE as u32)
+            .ok_or(PakError::BadHeaderSize { offset: header.base, expected: stored_size as u64 + RESOURCE_HEADER_SIZE, found: RESOURCE_HEADER_SIZE })?;
+        // garbled counts and lengths stop at the stored size instead of reading or allocating past it
+        let (base, size) = (header.base, header.size as u64);
+        let check_end = |end: u64| if end > base + size {
+            Err(PakError::BadHeaderSize { offset: base, expected: size, found: end - base })
+        } else {
+            Ok(())
+        };
         for _ in 0..meta_data_count {
             let mut entry = MetaData::default();
 
+            check_end(file.position()? + 24)?;
             entry.unk0 = file.read_u32()?;
             entry.unk1 = file.read_u128()?;
             let string_len = file.read_u32()?;
+            check_end(file.position()? + string_len as u64)?;
             entry.name_data = file.read_vector(string_len as usize)?;
 
             header.meta_data.push(entry);
         }
-        
-        debug_assert!(!NODE_CLASSES.contains(&&header.class[..]) || (meta_data_count == 0 && header.size == 0x40));
-        debug_assert_eq!(file.position()?, header.base + header.size as u64);
+
+        let header_end = file.position()?;
+        if header_end != header.base + header.size as u64 {
+            return Err(PakError::BadHeaderSize { offset: header.base, expected: header.size as u64, found: header_end - header.base });
+        }
 
         Ok(Some(header))
     }
 
-    /// Writes resource header to the file, will panic if the class is wrong
-    pub fn write<T: BinaryStream>(&self, file : &mut T) -> io::Result<()> {
+    /// Writes resource header to the file, the class has to fit in the header
+    pub fn write<T: BinaryStream>(&self, file : &mut T) -> PakResult<()> {
         const PAD : [u8; 0x20] = [0u8; 0x20];
 
-        debug_assert!(self.class.len() < 0x20);
-        file.write_u32(RESOURCE_MAGIC)?;
-
         let class = self.class.as_bytes();
-        debug_assert!(self.class.len() == class.len()); // it better be ASCII
+        if class.len() >= 0x20 {
+            return Err(PakError::InvalidClass { offset: file.position()?, class: self.class.clone() });
+        }
+        file.write_u32(RESOURCE_MAGIC)?;
         file.write_bytes(class)?;
         file.write_bytes(&PAD[class.len()..])?;
         
@@ -208,15 +400,13 @@ impl ResourceHeader {
 
         file.write_u32(self.meta_data.len() as u32)?;
 
-        let mut meta_data_size = 0;
-        for entry in &self.meta_data {
-            meta_data_size += 4 + 16 + 4 + entry.name_data.len();
-        }
-
-        file.write_u32(meta_data_size as u32)?;
+        // the size doesn't include the fixed part of the header, same as when reading
+        let meta_data_size: u32 = self.meta_data.iter().map(MetaData::size).sum();
+        file.write_u32(meta_data_size)?;
         for entry in &self.meta_data {
             file.write_u32(entry.unk0)?;
             file.write_u128(entry.unk1)?;
+            file.write_u32(entry.name_data.len() as u32)?;
             file.write_bytes(&entry.name_data[..])?;
         }
 
@@ -231,85 +421,361 @@ pub struct ResourceNode {
     #[getset(get = "pub")]
     header: ResourceHeader,
 
-    #[getset(get = "pub")]
-    children: Vec<ResourceChild>,
-
     /* Implementation detail */
 
+    /// nodes nested in the input file read their tables on first use
+    tables: OnceLock<PakResult<NodeTables>>,
+    /// where the tables are read from
+    source: Option<NodeSource>,
     state: NodeModifiedState,
+    /// children were removed or reordered
+    layout_changed: bool
+}
+
+/// Everything read from the tables of a node
+#[derive(Debug, Default)]
+struct NodeTables {
+    children: Vec<ResourceChild>,
+    /// position of the child offset table in the input file
+    table_offset: u64,
     data_offset: u64,
     header_len: u64,
-    data_len: u64,
+    data_len: u64
+}
+
+/// Where a nested node is in the input file
+#[derive(Debug, Default)]
+struct NodeSource {
+    input_file: Weak<SharedInput>,
+    base: u64,
+    /// including the header
+    size: u64
+}
+
+fn check_stream_delimiter<T: BinaryStream>(file: &mut T) -> PakResult<()> {
+    let offset = file.position()?;
+    let found = file.read_u8()?;
+    if found != 0x01 {
+        Err(PakError::BadDelimiter { offset: offset, found: found })
+    } else {
+        Ok(())
+    }
 }
 
-fn check_stream_delimiter<T: BinaryStream>(file: &mut T) -> io::Result<()> {
-    if file.read_u8()? != 0x01 {
-        Err(io::Error::new(io::ErrorKind::InvalidData, "Bad stream delimiter"))
+/// Reads a field that has the same value in every known pak
+fn check_version_field<T: BinaryStream>(file: &mut T, field: &'static str, expected: u32) -> PakResult<()> {
+    let offset = file.position()?;
+    let found = file.read_u32()?;
+    if found != expected {
+        Err(PakError::UnexpectedVersion { offset: offset, field: field, expected: expected, found: found })
     } else {
         Ok(())
     }
 }
 
+/// Checks a new child will be read back as the same type
+fn check_new_child(name: &str, contents: &ResourceType) -> io::Result<()> {
+    let (class, is_node) = match contents {
+        ResourceType::Resource(header) => (&header.class, false),
+        ResourceType::Node(node) => (&node.header.class, true),
+        _ => return Ok(())
+    };
+    if NODE_CLASSES.contains(&&class[..]) != is_node || class.len() >= 0x20 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("\"{}\" has invalid class \"{}\"", name, class)));
+    }
+    Ok(())
+}
+
 fn write_stream_delimiter<T: BinaryStream>(file: &mut T) -> io::Result<()> {
     file.write_u8(0x01)
 }
 
-fn copy_child_data<T: BinaryStream>(input: &Rc<RefCell<UncompressedFile>>, output : &mut T, child: &ResourceChild) -> io::Result<()> {
-    let mut input_file = input.borrow_mut();
-    input_file.seek(child.node_base + child.offset)?;
-    input_file.copy_data(output, child.size as usize)?;
+/// Where a child ended up in the file being written
+#[derive(Debug, Default, Clone, Copy)]
+struct ChildInfo {
+    size: u32,
+    offset: u64
+}
+
+fn copy_child_data<T: BinaryStream>(input: &SharedInput, output : &mut T, child: &ResourceChild) -> PakResult<()> {
+    let input_file = read_input(input);
+    let mut cursor = InputCursor::new(&input_file);
+    cursor.seek(child.node_base + child.offset)?;
+    cursor.copy_data(output, child.size as usize)?;
     Ok(())
 }
 
 impl ResourceNode {
+    /// Creates an empty node, the class has to be one of `NODE_CLASSES`
+    pub fn new(header: ResourceHeader) -> Self {
+        Self::with_children(header, Vec::new())
+    }
+
+    /// Creates a node that isn't in the input file yet
+    fn with_children(header: ResourceHeader, children: Vec<ResourceChild>) -> Self {
+        let mut node = ResourceNode::default();
+        node.header = header;
+        node.tables = OnceLock::from(Ok(NodeTables { children: children, ..NodeTables::default() }));
+        node.layout_changed = true;
+        node
+    }
+
+    /// Tables of the node, nested nodes read them from the input file on first use
+    fn tables(&self) -> &PakResult<NodeTables> {
+        self.tables.get_or_init(|| self.read_tables())
+    }
+
+    fn tables_mut(&mut self) -> PakResult<&mut NodeTables> {
+        self.tables();
+        self.tables.get_mut().unwrap().as_mut().map_err(|error| error.clone())
+    }
+
+    /// Tables if they were read already
+    fn loaded_tables_mut(&mut self) -> Option<&mut NodeTables> {
+        self.tables.get_mut().and_then(|tables| tables.as_mut().ok())
+    }
+
+    fn read_tables(&self) -> PakResult<NodeTables> {
+        let source = match &self.source {
+            Some(source) => source,
+            None => return Ok(NodeTables::default())
+        };
+        let file_ref = source.input_file.upgrade().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "The pak was closed"))?;
+        let mut tables = {
+            let file = read_input(&file_ref);
+            let mut cursor = InputCursor::new(&file);
+            cursor.seek(source.base + self.header.size as u64)?;
+            NodeTables::read(&mut cursor, source.base, source.size)?
+        };
+        tables.set_stream_ref(source.input_file.clone());
+        Ok(tables)
+    }
+
+    /// Reads the tables of a nested node if they weren't read yet
+    pub fn load(&self) -> PakResult<()> {
+        self.tables().as_ref().map(|_| ()).map_err(|error| error.clone())
+    }
+
+    /// Have the tables been read, or failed to read
+    pub fn is_loaded(&self) -> bool {
+        self.tables.get().is_some()
+    }
+
+    /// A node whose tables can't be read has no children, `load` tells why
+    pub fn children(&self) -> &[ResourceChild] {
+        match self.tables() {
+            Ok(tables) => &tables.children[..],
+            Err(_) => &[]
+        }
+    }
+
     pub fn children_mut(&mut self) -> &mut[ResourceChild] {
-        &mut self.children[..]
+        match self.tables_mut() {
+            Ok(tables) => &mut tables.children[..],
+            Err(_) => &mut []
+        }
+    }
+
+    /// Appends a new Data, Resource or Node child and returns its index, data is ignored for nodes
+    pub fn add_child(&mut self, name: String, mut contents: ResourceType, data: Vec<u8>) -> io::Result<usize> {
+        self.load()?;
+        if self.find_child(&name).is_some() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("\"{}\" already exists", name)));
+        }
+        check_new_child(&name, &contents)?;
+
+        let data = match &mut contents {
+            ResourceType::Link(_) => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Links can't be added")),
+            ResourceType::Node(node) => {
+                node.layout_changed = true;
+                None
+            },
+            _ => Some(data)
+        };
+        let children = &mut self.tables_mut()?.children;
+        children.push(ResourceChild::new_entry(name, contents, data));
+        Ok(children.len() - 1)
+    }
+
+    /// Find a child by name
+    pub fn find_child(&self, name: &str) -> Option<&ResourceChild> {
+        self.children().iter().find(|child| child.name == name)
+    }
+
+    /// Find a child by name
+    pub fn find_child_mut(&mut self, name: &str) -> Option<&mut ResourceChild> {
+        self.children_mut().iter_mut().find(|child| child.name == name)
+    }
+
+    /// Indices of the links pointing at a child
+    fn links_to(&self, index: usize) -> Vec<usize> {
+        let children = self.children();
+        (0..children.len()).filter(|other_index| {
+            match children[*other_index].contents {
+                ResourceType::Link(target) => target == index,
+                _ => false
+            }
+        }).collect()
+    }
+
+    /// Rearranges the children, `new_order` lists the old indices of the children to keep in their new order
+    fn reorder_children(&mut self, new_order: &[usize]) {
+        let children = match self.tables_mut() {
+            Ok(tables) => &mut tables.children,
+            Err(_) => return
+        };
+        let mut new_index = vec![None; children.len()];
+        for (index, old_index) in new_order.iter().enumerate() {
+            new_index[*old_index] = Some(index);
+        }
+
+        let mut old_children: Vec<Option<ResourceChild>> = children.drain(..).map(Some).collect();
+        for old_index in new_order {
+            children.push(old_children[*old_index].take().unwrap());
+        }
+
+        let mut is_linked = vec![false; children.len()];
+        for child in children.iter_mut() {
+            if let ResourceType::Link(target) = &mut child.contents {
+                // callers never drop a child that is still linked to
+                *target = new_index[*target].unwrap();
+                is_linked[*target] = true;
+            }
+        }
+        for (child, is_linked) in children.iter_mut().zip(is_linked) {
+            child.has_active_links = is_linked;
+        }
+        self.layout_changed = true;
+    }
+
+    /// Removes a child, children that are linked to can only be removed along with the
+    /// links pointing at them by setting `cascade`
+    pub fn remove_child(&mut self, index: usize, cascade: bool) -> io::Result<()> {
+        self.load()?;
+        if index >= self.children().len() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "No such child"));
+        }
+
+        let mut removed = self.links_to(index);
+        if !removed.is_empty() && !cascade {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("\"{}\" is linked to by {} other children", self.children()[index].name, removed.len())));
+        }
+        removed.push(index);
+
+        let new_order: Vec<usize> = (0..self.children().len()).filter(|old_index| !removed.contains(old_index)).collect();
+        self.reorder_children(&new_order);
+        Ok(())
+    }
+
+    /// Moves a child to a new index, shifting the children in between
+    pub fn move_child(&mut self, from: usize, to: usize) -> io::Result<()> {
+        self.load()?;
+        let child_count = self.children().len();
+        if from >= child_count || to >= child_count {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "No such child"));
+        }
+
+        let mut new_order: Vec<usize> = (0..child_count).collect();
+        let moved = new_order.remove(from);
+        new_order.insert(to, moved);
+        self.reorder_children(&new_order);
+        Ok(())
     }
 
     fn update_state(&mut self) {
-        // DEBUG!!
-        self.state = NodeModifiedState::Full;
-        for child in &mut self.children {
-            if child.meta_data_dirty {
-                if self.state == NodeModifiedState::Clean {
-                    self.state = NodeModifiedState::Header
+        self.state = if self.layout_changed {
+            NodeModifiedState::Full
+        } else if self.header.dirty {
+            NodeModifiedState::Header
+        } else {
+            NodeModifiedState::Clean
+        };
+        // nodes that weren't read can't have changed children
+        let tables = match self.tables.get_mut() {
+            Some(Ok(tables)) => tables,
+            _ => return
+        };
+        for child in &mut tables.children {
+            let mut child_state = if child.is_new_entry {
+                if let ResourceType::Node(node) = &mut child.contents {
+                    node.update_state();
                 }
-            }
-            match &mut child.contents {
-                ResourceType::Data => {
-                    if child.new_data.is_some() {
-                        if child.is_new_entry && self.state != NodeModifiedState::Full {
-                            self.state = NodeModifiedState::Append;
+                NodeModifiedState::Append
+            } else {
+                let header_changed = child.header_changed();
+                match &mut child.contents {
+                    ResourceType::Node(node) => {
+                        node.update_state();
+                        if node.state != NodeModifiedState::Clean {
+                            NodeModifiedState::Full
                         } else {
-                            self.state = NodeModifiedState::Full;
+                            NodeModifiedState::Clean
                         }
-                    }
-                },
-
-                ResourceType::Node(node) => {
-                    node.update_state();
-                    if node.state != NodeModifiedState::Clean {
-                        self.state = NodeModifiedState::Full;
-                    }
-                },
+                    },
+                    _ if child.new_data.is_some() || header_changed => NodeModifiedState::Full,
+                    _ => NodeModifiedState::Clean
+                }
+            };
 
-                _=> {}
+            if child.meta_data_dirty {
+                child_state = std::cmp::max(child_state, NodeModifiedState::Header);
             }
+            self.state = std::cmp::max(self.state.clone(), child_state);
         }
     }
 
-    fn set_stream_ref(&mut self, stream: Weak<RefCell<UncompressedFile>>) {
-        for child in &mut self.children {
-            child.input_file = stream.clone();
-            if let ResourceType::Node(node) = &mut child.contents {
-                node.set_stream_ref(stream.clone());
+    fn set_stream_ref(&mut self, stream: Weak<SharedInput>) {
+        if let Some(source) = &mut self.source {
+            source.input_file = stream.clone();
+        }
+        if let Some(tables) = self.loaded_tables_mut() {
+            tables.set_stream_ref(stream);
+        }
+    }
+
+    /// Writes the data of a single child at the current position, updating its offset and size
+    fn write_child<T: BinaryStream>(&self, interface : &PakInterface, file : &mut T, offset_start: u64, child: &ResourceChild, info: &mut ChildInfo) -> PakResult<()> {
+        let offset = file.position()? - offset_start;
+        match &child.contents {
+            ResourceType::Data => {
+                match &child.new_data {
+                    Some(data) => { file.write_bytes(data)?; }
+                    None => { copy_child_data(interface.input()?, file, child)?; }
+                }
+            },
+            ResourceType::Resource(header) => {
+                match &child.new_data {
+                    Some(data) => { 
+                        header.write(file)?;
+                        file.write_bytes(data)?; 
+                        info.size = (file.position()? - offset_start - offset) as u32;
+                    }
+                    // the header changed length, the data is copied from after the old one
+                    None if header.dirty => {
+                        header.write(file)?;
+                        child.with_data(|data| file.write_bytes(data))??;
+                        info.size = (file.position()? - offset_start - offset) as u32;
+                    }
+                    None => { copy_child_data(interface.input()?, file, child)?; }
+                }
+            },
+            ResourceType::Node(node) => {
+                if node.state != NodeModifiedState::Clean {
+                    info.size = node.write(interface, file)? as u32;
+                } else {
+                    copy_child_data(interface.input()?, file, child)?;
+                }
             }
+            _=> return Ok(())
         }
+        info.offset = offset;
+        Ok(())
     }
 
-    fn write<T: BinaryStream>(&self, interface : &PakInterface, file : &mut T) -> io::Result<u64> {
+    fn write<T: BinaryStream>(&self, interface : &PakInterface, file : &mut T) -> PakResult<u64> {
+        let tables = self.tables().as_ref().map_err(|error| error.clone())?;
         let offset_start = file.position()?;
-        let child_count = self.children.len();
+        let child_count = tables.children.len();
 
         // write basic header
         self.header.write(file)?;
@@ -321,7 +787,7 @@ impl ResourceNode {
 
         // write file list
 
-        for child in &self.children {
+        for child in &tables.children {
             file.write_string(&child.name)?;
         }
 
@@ -335,66 +801,63 @@ impl ResourceNode {
 
         write_stream_delimiter(file)?;
 
-        for child in &self.children {
+        for child in &tables.children {
             match child.contents {
                 ResourceType::Link(_) => { file.write_u32(1) }
                 _=> { file.write_u32(0) }
             }?
         }
 
-        #[derive(Debug, Default, Clone, Copy)]
-        struct ChildInfo {
-            size: u32,
-            offset: u64
-        }
         let mut new_info = vec![ChildInfo::default(); child_count];
         // copy old offset and size info
         for child_index in 0..child_count {
-            new_info[child_index].offset = self.children[child_index].offset;
-            new_info[child_index].size = self.children[child_index].size;
+            new_info[child_index].offset = tables.children[child_index].offset;
+            new_info[child_index].size = tables.children[child_index].size;
         }
 
-        if self.state == NodeModifiedState::Clean {
-            let mut input_file = interface.input_file.borrow_mut();
-            input_file.seek(self.data_offset)?;
-            input_file.copy_data(file, self.data_len as usize)?;
-        } else { // todo use the other state info or remove it
-
-            // write child data
+        if self.state == NodeModifiedState::Full {
             for child_index in 0..child_count {
-                let child = &self.children[child_index];
-                new_info[child_index].offset = file.position()? - offset_start;
-                match &child.contents {
-                    ResourceType::Data => {
-                        match &child.new_data {
-                            Some(data) => { file.write_bytes(data)?; }
-                            None => { copy_child_data(&interface.input_file, file, child)?; }
-                        }
-                    },
-                    ResourceType::Resource(header) => {
-                        match &child.new_data {
-                            Some(data) => { 
-                                header.write(file)?;
-                                file.write_bytes(data)?; 
-                            }
-                            None => { copy_child_data(&interface.input_file, file, child)?; }
-                        }
-                    },
-                    ResourceType::Node(node) => {
-                        if node.state != NodeModifiedState::Clean {
-                            new_info[child_index].size = node.write(interface, file)? as u32;
-                        } else {
-                            copy_child_data(&interface.input_file, file, child)?;
-                        }
-                    }
-                    _=> continue
+                self.write_child(interface, file, offset_start, &tables.children[child_index], &mut new_info[child_index])?;
+            }
+        } else {
+            // existing data is copied in one go, only the length of the header can have changed
+            let data_start = file.position()? - offset_start;
+            // children starting inside the old tables aren't part of that data, empty ones keep
+            // their offset and the others are written after it
+            let mut outside_data = vec![false; child_count];
+            for child_index in 0..child_count {
+                let child = &tables.children[child_index];
+                if child.is_new_entry {
+                    continue;
                 }
+                match child.offset.checked_sub(tables.header_len) {
+                    Some(data_offset) => new_info[child_index].offset = data_start + data_offset,
+                    None => outside_data[child_index] = child.size != 0 && !matches!(child.contents, ResourceType::Link(_))
+                }
+            }
+            {
+                let input_file = read_input(interface.input()?);
+                let mut cursor = InputCursor::new(&input_file);
+                cursor.seek(tables.data_offset)?;
+                cursor.copy_data(file, tables.data_len as usize)?;
             }
 
-            // update links
             for child_index in 0..child_count {
-                if let ResourceType::Link(idx) = self.children[child_index].contents {
-                    new_info[child_index].offset = new_info[idx].offset;
+                if tables.children[child_index].is_new_entry || outside_data[child_index] {
+                    self.write_child(interface, file, offset_start, &tables.children[child_index], &mut new_info[child_index])?;
+                }
+            }
+        }
+
+        // update links, they take the size of their target if it was rewritten
+        for child_index in 0..child_count {
+            if let ResourceType::Link(idx) = tables.children[child_index].contents {
+                let target = &tables.children[idx];
+                let target_rewritten = target.is_new_entry || target.new_data.is_some() || target.header_changed() ||
+                    matches!(&target.contents, ResourceType::Node(node) if node.state != NodeModifiedState::Clean);
+                new_info[child_index].offset = new_info[idx].offset;
+                if target_rewritten {
+                    new_info[child_index].size = new_info[idx].size;
                 }
             }
         }
@@ -417,19 +880,29 @@ impl ResourceNode {
         Ok(end - offset_start)
     }
     
-    fn read<T: BinaryStream>(file: &mut T, header: ResourceHeader) ->io::Result<Self> {
+    /// Reads the node following `header`, `node_size` is the size of the node including the header.
+    /// Nested nodes only get their header read, their tables are read on first use.
+    fn read<T: BinaryStream>(file: &mut T, header: ResourceHeader, node_size: u64) -> PakResult<Self> {
         // children are relative to node base
         let node_base = file.position()? - header.size as u64;
 
         let mut node = Self::default();
-
         node.header = header;
+        node.tables = OnceLock::from(Ok(NodeTables::read(file, node_base, node_size)?));
+        Ok(node)
+    }
+}
+
+impl NodeTables {
+    /// Reads the tables following the header of the node at `node_base`
+    fn read<T: BinaryStream>(file: &mut T, node_base: u64, node_size: u64) -> PakResult<Self> {
+        let mut tables = Self::default();
 
         check_stream_delimiter(file)?;
 
-        assert_eq!(file.read_u32()?, 0x1000000);
+        check_version_field(file, "node version", 0x1000000)?;
         let child_count = file.read_u32()?;
-        assert_eq!(file.read_u32()?, 4);
+        check_version_field(file, "offset table entry size", 4)?;
 
         check_stream_delimiter(file)?;
 
@@ -438,18 +911,19 @@ impl ResourceNode {
             child.node_base = node_base;
             child.name = file.read_string()?;
 
-            node.children.push(child);
+            tables.children.push(child);
         }
         check_stream_delimiter(file)?;
 
+        tables.table_offset = file.position()?;
         for child_index in 0..child_count as usize {
-            node.children[child_index].offset = file.read_u64()?;
+            tables.children[child_index].offset = file.read_u64()?;
         }
 
         check_stream_delimiter(file)?;
 
         for child_index in 0..child_count as usize {
-            node.children[child_index].size = file.read_u32()?;
+            tables.children[child_index].size = file.read_u32()?;
         }
 
         check_stream_delimiter(file)?;
@@ -458,46 +932,86 @@ impl ResourceNode {
             is_link[child_index] = file.read_u32()? > 0;
         }
 
-        node.data_offset = file.position()?;
-        node.header_len = node.data_offset - node_base;
+        tables.data_offset = file.position()?;
+        tables.header_len = tables.data_offset - node_base;
+        if tables.header_len > node_size {
+            return Err(PakError::BadHeaderSize { offset: node_base, expected: node_size, found: tables.header_len });
+        }
+        tables.data_len = node_size - tables.header_len;
+
+        // children past the end of their node are left to `validate`, only data that isn't
+        // in the file at all is skipped
+        let file_length = file.length()?;
+        for child in &mut tables.children {
+            child.out_of_range = node_base.checked_add(child.offset).and_then(|start| start.checked_add(child.size as u64)).map_or(true, |end| end > file_length);
+        }
 
         for child_index in 0..child_count as usize {
-            if !is_link[child_index] {
-                let node_file_offset = node_base + node.children[child_index].offset;
+            if !is_link[child_index] && !tables.children[child_index].out_of_range {
+                // too small to have a header, don't read past the end of it
+                if (tables.children[child_index].size as u64) < RESOURCE_HEADER_SIZE {
+                    continue;
+                }
+                let node_file_offset = node_base + tables.children[child_index].offset;
                 file.seek(node_file_offset)?;
                 if let Some(header) = ResourceHeader::read(file)? {
                     if NODE_CLASSES.contains(&&header.class[..]) {
-                        let mut child_node = Self::read(file, header)?;
-                        child_node.data_len = node.children[child_index].size as u64 - (child_node.data_offset - node_file_offset);
-                        node.children[child_index].contents = ResourceType::Node(child_node);
+                        let mut child_node = ResourceNode::default();
+                        child_node.header = header;
+                        child_node.source = Some(NodeSource { input_file: Weak::new(), base: node_file_offset, size: tables.children[child_index].size as u64 });
+                        tables.children[child_index].contents = ResourceType::Node(child_node);
                     } else {
-                        node.children[child_index].contents = ResourceType::Resource(header);
+                        tables.children[child_index].header_len = header.size;
+                        tables.children[child_index].contents = ResourceType::Resource(header);
                     }
                 }
-            } else {
-                for other_child_index in 0..child_count as usize {
-                    if !is_link[other_child_index] &&
-                            node.children[other_child_index].offset == node.children[child_index].offset {
-                        node.children[child_index].contents = ResourceType::Link(other_child_index);
-                        node.children[other_child_index].has_active_links = true; // prevent it from being moved or deleted
-                        break;
-                    }
+            }
+        }
+
+        // links point at the first child that starts at the same offset
+        let mut targets = HashMap::new();
+        for child_index in (0..child_count as usize).rev() {
+            if !is_link[child_index] {
+                targets.insert(tables.children[child_index].offset, child_index);
+            }
+        }
+        for child_index in 0..child_count as usize {
+            if is_link[child_index] {
+                match targets.get(&tables.children[child_index].offset) {
+                    Some(&target) => {
+                        tables.children[child_index].contents = ResourceType::Link(target);
+                        tables.children[target].has_active_links = true; // prevent it from being moved or deleted
+                    },
+                    None => tables.children[child_index].dangling_link = true
                 }
             }
         }
-        Ok(node)
+        Ok(tables)
+    }
+
+    fn set_stream_ref(&mut self, stream: Weak<SharedInput>) {
+        for child in &mut self.children {
+            child.input_file = stream.clone();
+            if let ResourceType::Node(node) = &mut child.contents {
+                node.set_stream_ref(stream.clone());
+            }
+        }
     }
 }
 
 #[derive(Debug)]
 pub struct PakInterface {
-    input_file: std::rc::Rc<RefCell<UncompressedFile>>,
-    root_node: ResourceNode
+    /// None for paks built from scratch
+    input_file: Option<Arc<SharedInput>>,
+    root_node: ResourceNode,
+    /// built by `find_by_uuid`, dropped whenever the tree can change
+    uuid_index: OnceLock<uuid_index::UuidIndex>
 }
 
 #[allow(dead_code)]
 impl PakInterface {
     pub fn get_root_node_mut(&mut self) -> &mut ResourceNode {
+        self.uuid_index.take();
         &mut self.root_node
     }
 
@@ -505,23 +1019,62 @@ impl PakInterface {
         &self.root_node
     }
 
-    pub fn open(file : File) -> io::Result<PakInterface> {
-        let mut file = UncompressedFile::new(file);
-        if let Some(header_data) = ResourceHeader::read(&mut file)? {
-            let mut root_node = ResourceNode::read(&mut file, header_data)?;
-            root_node.data_len = file.length()? - root_node.data_offset;
-            let stream_ref = Rc::new(RefCell::new(file));
-            root_node.set_stream_ref(Rc::downgrade(&stream_ref));
-            Ok(PakInterface { input_file: stream_ref, root_node: root_node })
+    /// The pak we were opened from
+    fn input(&self) -> io::Result<&Arc<SharedInput>> {
+        self.input_file.as_ref().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Pak wasn't opened from a file"))
+    }
+
+    /// Wraps a tree made with `NodeBuilder`, so it can be saved
+    pub fn from_root_node(root_node: ResourceNode) -> PakInterface {
+        PakInterface { input_file: None, root_node: root_node, uuid_index: OnceLock::new() }
+    }
+
+    /// Opens a pak, compressed paks are decompressed on the fly
+    pub fn open(file : File) -> PakResult<PakInterface> {
+        PakInterface::open_input(InputFile::open(file)?)
+    }
+
+    /// Opens a pak from any stream, the whole stream is read into memory first so nothing
+    /// is read from it afterwards
+    pub fn open_from<T: BinaryStream>(mut stream: T) -> PakResult<PakInterface> {
+        let length = usize::try_from(stream.length()?).map_err(|_| io::Error::new(io::ErrorKind::OutOfMemory, "Pak doesn't fit in memory"))?;
+        stream.seek(0)?;
+        PakInterface::open_input(InputFile::from_bytes(stream.read_vector(length)?)?)
+    }
+
+    fn open_input(file: InputFile) -> PakResult<PakInterface> {
+        let mut cursor = InputCursor::new(&file);
+        if let Some(header_data) = ResourceHeader::read(&mut cursor)? {
+            let length = file.length()?;
+            let mut root_node = ResourceNode::read(&mut cursor, header_data, length)?;
+            let stream_ref = Arc::new(RwLock::new(file));
+            root_node.set_stream_ref(Arc::downgrade(&stream_ref));
+            Ok(PakInterface { input_file: Some(stream_ref), root_node: root_node, uuid_index: OnceLock::new() })
         } else {
-            Err(io::Error::new(io::ErrorKind::InvalidData, "Bad header"))
+            cursor.seek(0)?;
+            Err(PakError::BadMagic { found: cursor.read_u32()? })
         }
     }
 
-    pub fn save(&mut self, file : File) -> io::Result<()> {
-        let mut file = UncompressedFile::new(file);
+    pub fn save(&mut self, file : File) -> PakResult<()> {
+        self.save_to(UncompressedFile::new(file))?;
+        Ok(())
+    }
+
+    /// Writes the pak to any stream, returning the stream so a `MemoryStream` can be
+    /// taken apart afterwards
+    pub fn save_to<T: BinaryStream>(&mut self, mut stream: T) -> PakResult<T> {
+        self.root_node.update_state();
+        self.root_node.write(self, &mut stream)?;
+        Ok(stream)
+    }
+
+    /// Saves the pak in the compressed layout the game loads, level is the zlib level (0-9)
+    pub fn save_compressed(&mut self, file : File, level: u32) -> PakResult<()> {
+        let mut file = CompressedWriter::new(UncompressedFile::new(file), level)?;
         self.root_node.update_state();
         self.root_node.write(self, &mut file)?;
+        file.finish()?;
         Ok(())
     }
 }
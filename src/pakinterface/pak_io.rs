@@ -1,5 +1,6 @@
-use std::{io::{self, Read, Seek, SeekFrom, Write}};
+use std::{convert::TryFrom, io::{self, Read, Seek, SeekFrom, Write}, sync::{Arc, Mutex, PoisonError}};
 use crate::util::{BinaryStream};
+use super::{RESOURCE_MAGIC, PakError, PakResult};
 
 #[derive(Debug)]
 pub struct UncompressedFile {
@@ -10,6 +11,16 @@ impl UncompressedFile {
     pub fn new(file: std::fs::File) -> Self {
         UncompressedFile { file : file }
     }
+
+    /// Waits for all writes to reach the disk
+    pub fn sync(&self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+
+    /// Truncates or extends the file
+    pub fn set_length(&mut self, length: u64) -> io::Result<()> {
+        self.file.set_len(length)
+    }
 }
 
 impl BinaryStream for UncompressedFile {
@@ -34,3 +45,606 @@ impl BinaryStream for UncompressedFile {
         self.file.stream_len()
     }
 }
+
+/// Buffer a `MemoryStream` can be backed by
+pub trait MemoryBuffer: AsRef<[u8]> {
+    /// Writes `buf` at `offset`, growing the buffer if needed
+    fn write_at(&mut self, offset: usize, buf: &[u8]) -> io::Result<()>;
+}
+
+impl MemoryBuffer for Vec<u8> {
+    fn write_at(&mut self, offset: usize, buf: &[u8]) -> io::Result<()> {
+        let end = offset + buf.len();
+        if self.len() < end {
+            // anything we skipped over is zero, as it would be in a file
+            self.resize(end, 0);
+        }
+        self[offset..end].copy_from_slice(buf);
+        Ok(())
+    }
+}
+
+impl MemoryBuffer for &mut Vec<u8> {
+    fn write_at(&mut self, offset: usize, buf: &[u8]) -> io::Result<()> {
+        (**self).write_at(offset, buf)
+    }
+}
+
+impl MemoryBuffer for &[u8] {
+    fn write_at(&mut self, _offset: usize, _buf: &[u8]) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Other, "Borrowed buffers are read only"))
+    }
+}
+
+/// Stream over bytes in memory, a `Vec<u8>` can be read and written, a `&[u8]` only read
+#[derive(Debug, Default)]
+pub struct MemoryStream<T: MemoryBuffer> {
+    data: T,
+    position: u64
+}
+
+#[allow(dead_code)]
+impl<T: MemoryBuffer> MemoryStream<T> {
+    pub fn new(data: T) -> Self {
+        MemoryStream { data: data, position: 0 }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.data
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data
+    }
+}
+
+impl<T: MemoryBuffer> BinaryStream for MemoryStream<T> {
+    fn read_bytes(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        buf.copy_from_slice(slice_at(self.data.as_ref(), self.position, buf.len())?);
+        self.position += buf.len() as u64;
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, buf: &[u8]) -> io::Result<()> {
+        let offset = usize::try_from(self.position).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Position doesn't fit in memory"))?;
+        self.data.write_at(offset, buf)?;
+        self.position += buf.len() as u64;
+        Ok(())
+    }
+
+    fn seek(&mut self, pos: u64) -> io::Result<()> {
+        self.position = pos;
+        Ok(())
+    }
+
+    fn position(&mut self) -> io::Result<u64> {
+        Ok(self.position)
+    }
+
+    fn length(&mut self) -> io::Result<u64> {
+        Ok(self.data.as_ref().len() as u64)
+    }
+}
+
+/// Reads at `offset` without using the file cursor, so readers on several threads can share the file
+fn read_exact_at(file: &std::fs::File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+    }
+    #[cfg(windows)]
+    {
+        // seek_read moves the cursor on Windows but nothing reading an input file uses it
+        let mut bytes_read = 0;
+        while bytes_read < buf.len() {
+            match std::os::windows::fs::FileExt::seek_read(file, &mut buf[bytes_read..], offset + bytes_read as u64)? {
+                0 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "failed to fill whole buffer")),
+                count => bytes_read += count
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Uncompressed pak mapped into memory, reads are copies out of the map instead of syscalls
+///
+/// The map assumes nothing else truncates the file while it is open.
+#[derive(Debug)]
+pub struct MappedFile {
+    file: std::fs::File,
+    map: memmap2::Mmap
+}
+
+impl MappedFile {
+    pub fn new(file: std::fs::File) -> io::Result<Self> {
+        let map = unsafe { memmap2::Mmap::map(&file)? };
+        Ok(MappedFile { file: file, map: map })
+    }
+
+    /// The bytes at `offset`, without copying them
+    pub fn slice(&self, offset: u64, size: usize) -> io::Result<&[u8]> {
+        slice_at(&self.map, offset, size)
+    }
+}
+
+/// `size` bytes of `data` at `offset`, failing if they run past the end
+fn slice_at(data: &[u8], offset: u64, size: usize) -> io::Result<&[u8]> {
+    let end = offset.checked_add(size as u64).filter(|end| *end <= data.len() as u64)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "Read past end of input"))?;
+    Ok(&data[offset as usize..end as usize])
+}
+
+/// Location of a single compressed chunk
+#[derive(Debug, Clone, Copy)]
+struct ChunkInfo {
+    /// offset of the zlib stream in the compressed file
+    file_offset: u64,
+    /// size of the zlib stream
+    compressed_size: u64,
+    /// offset of the chunk in the decompressed stream
+    offset: u64,
+    /// decompressed size of the chunk
+    size: u32
+}
+
+/// Read only view of a compressed pak
+///
+/// The container starts with a u32 chunk count followed by a table of u64 file offsets,
+/// one per chunk. Each chunk is a u32 decompressed size followed by a zlib stream (with the
+/// zlib header) that runs up to the next chunk or the end of the file. All numbers are little
+/// endian. Chunks we write hold `COMPRESSED_CHUNK_SIZE` bytes except the last one, but any
+/// sizes are read. `compressed_container_layout` in the tests spells out a small container
+/// byte by byte.
+#[derive(Debug)]
+pub struct CompressedFile {
+    /// the compressed bytes, a plain file or a buffer
+    source: Box<InputFile>,
+    chunks: Vec<ChunkInfo>,
+    length: u64,
+
+    /// index and contents of the last chunk we decompressed
+    cached_chunk: Mutex<Option<(usize, Arc<Vec<u8>>)>>
+}
+
+impl CompressedFile {
+    /// Reads the chunk table, fails if the file doesn't look like a compressed pak
+    pub fn new(source: InputFile) -> io::Result<Self> {
+        let mut file = InputCursor::new(&source);
+        let file_length = file.length()?;
+
+        file.seek(0)?;
+        let chunk_count = file.read_u32()? as u64;
+        let table_end = 4 + chunk_count * 8;
+        if chunk_count == 0 || table_end > file_length {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Bad chunk table"));
+        }
+
+        let mut chunk_offsets = Vec::with_capacity(chunk_count as usize);
+        for _ in 0..chunk_count {
+            chunk_offsets.push(file.read_u64()?);
+        }
+
+        let mut chunks = Vec::with_capacity(chunk_count as usize);
+        let mut length = 0u64;
+        for chunk_index in 0..chunk_offsets.len() {
+            let start = chunk_offsets[chunk_index];
+            let end = match chunk_offsets.get(chunk_index + 1) {
+                Some(next) => *next,
+                None => file_length
+            };
+            if start < table_end || end < start + 4 || end > file_length {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Bad chunk offset"));
+            }
+
+            file.seek(start)?;
+            let size = file.read_u32()?;
+            if size == 0 {
+                continue;
+            }
+            chunks.push(ChunkInfo { file_offset: start + 4, compressed_size: end - start - 4, offset: length, size: size });
+            length += size as u64;
+        }
+
+        Ok(CompressedFile { source: Box::new(source), chunks: chunks, length: length, cached_chunk: Mutex::new(None) })
+    }
+
+    /// Index of the chunk containing `pos`
+    fn chunk_index(&self, pos: u64) -> usize {
+        match self.chunks.binary_search_by(|chunk| chunk.offset.cmp(&pos)) {
+            Ok(index) => index,
+            Err(index) => index - 1
+        }
+    }
+
+    /// Decompresses a chunk unless it's the one in the cache, the cache is only locked
+    /// while it is looked at so threads can decompress different chunks at once
+    fn load_chunk(&self, chunk_index: usize) -> io::Result<Arc<Vec<u8>>> {
+        if let Some((index, data)) = &*self.cached_chunk.lock().unwrap_or_else(PoisonError::into_inner) {
+            if *index == chunk_index {
+                return Ok(data.clone());
+            }
+        }
+
+        let chunk = self.chunks[chunk_index];
+        let mut compressed = vec![0u8; chunk.compressed_size as usize];
+        self.source.read_at(chunk.file_offset, &mut compressed)?;
+
+        let mut data = vec![0u8; chunk.size as usize];
+        flate2::read::ZlibDecoder::new(&compressed[..]).read_exact(&mut data)?;
+        let data = Arc::new(data);
+        *self.cached_chunk.lock().unwrap_or_else(PoisonError::into_inner) = Some((chunk_index, data.clone()));
+        Ok(data)
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        if offset + buf.len() as u64 > self.length {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Read past end of compressed stream"));
+        }
+
+        let mut bytes_read = 0;
+        while bytes_read < buf.len() {
+            let position = offset + bytes_read as u64;
+            let chunk_index = self.chunk_index(position);
+            let chunk_offset = (position - self.chunks[chunk_index].offset) as usize;
+            let data = self.load_chunk(chunk_index)?;
+
+            let bytes_to_copy = std::cmp::min(buf.len() - bytes_read, data.len() - chunk_offset);
+            buf[bytes_read..bytes_read + bytes_to_copy].copy_from_slice(&data[chunk_offset..chunk_offset + bytes_to_copy]);
+            bytes_read += bytes_to_copy;
+        }
+        Ok(())
+    }
+}
+
+/// Pak we are reading from, compressed or not, in a file or in memory
+///
+/// Reads take the offset instead of seeking, so one input file can be read from several
+/// threads at once. Use an `InputCursor` where a `BinaryStream` is needed.
+#[derive(Debug)]
+pub enum InputFile {
+    Uncompressed(std::fs::File),
+    Mapped(MappedFile),
+    Memory(Vec<u8>),
+    Compressed(CompressedFile)
+}
+
+impl InputFile {
+    /// Opens the pak, detecting if it's compressed or not. Uncompressed paks are memory
+    /// mapped, falling back to plain reads where the file can't be mapped.
+    pub fn open(file: std::fs::File) -> PakResult<Self> {
+        let mut input = InputFile::Uncompressed(file);
+        let magic = InputCursor::new(&input).read_u32()?;
+        if magic == RESOURCE_MAGIC {
+            input.map();
+            return Ok(input);
+        }
+        InputFile::decompress(input, magic)
+    }
+
+    /// Same as `open` for a pak that is already in memory
+    pub fn from_bytes(data: Vec<u8>) -> PakResult<Self> {
+        let input = InputFile::Memory(data);
+        let magic = InputCursor::new(&input).read_u32()?;
+        if magic == RESOURCE_MAGIC {
+            return Ok(input);
+        }
+        InputFile::decompress(input, magic)
+    }
+
+    /// Opens `source` as a compressed pak, `magic` is what it starts with, for the error
+    fn decompress(source: InputFile, magic: u32) -> PakResult<Self> {
+        // anything that isn't a compressed pak either fails here or decompresses to the wrong magic
+        let compressed = CompressedFile::new(source).map_err(|error| match error.kind() {
+            io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => PakError::BadMagic { found: magic },
+            _ => PakError::Io(error)
+        })?;
+        let input = InputFile::Compressed(compressed);
+        if InputCursor::new(&input).read_u32()? != RESOURCE_MAGIC {
+            return Err(PakError::BadMagic { found: magic });
+        }
+        Ok(input)
+    }
+
+    /// Switches plain reads to a memory map if the file can be mapped, the map covers the
+    /// file as it is now
+    pub fn map(&mut self) {
+        if let InputFile::Uncompressed(file) = self {
+            if let Ok(mapped) = file.try_clone().and_then(MappedFile::new) {
+                *self = InputFile::Mapped(mapped);
+            }
+        }
+    }
+
+    /// Switches a memory map back to plain reads, the file can't be replaced or grown
+    /// while it is mapped on every platform
+    pub fn unmap(&mut self) -> io::Result<()> {
+        if let InputFile::Mapped(mapped) = self {
+            *self = InputFile::Uncompressed(mapped.file.try_clone()?);
+        }
+        Ok(())
+    }
+
+    /// The bytes at `offset` when they can be borrowed without copying
+    pub fn mapped_slice(&self, offset: u64, size: usize) -> Option<io::Result<&[u8]>> {
+        match self {
+            InputFile::Mapped(mapped) => Some(mapped.slice(offset, size)),
+            InputFile::Memory(data) => Some(slice_at(data, offset, size)),
+            _ => None
+        }
+    }
+
+    pub fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        match self {
+            InputFile::Uncompressed(file) => read_exact_at(file, buf, offset),
+            InputFile::Mapped(file) => {
+                buf.copy_from_slice(file.slice(offset, buf.len())?);
+                Ok(())
+            },
+            InputFile::Memory(data) => {
+                buf.copy_from_slice(slice_at(data, offset, buf.len())?);
+                Ok(())
+            },
+            InputFile::Compressed(file) => file.read_at(offset, buf)
+        }
+    }
+
+    pub fn length(&self) -> io::Result<u64> {
+        match self {
+            InputFile::Uncompressed(file) => Ok(file.metadata()?.len()),
+            InputFile::Mapped(file) => Ok(file.map.len() as u64),
+            InputFile::Memory(data) => Ok(data.len() as u64),
+            InputFile::Compressed(file) => Ok(file.length)
+        }
+    }
+}
+
+/// Read position in an input file, each reader keeps its own
+#[derive(Debug)]
+pub struct InputCursor<'a> {
+    input: &'a InputFile,
+    position: u64
+}
+
+impl<'a> InputCursor<'a> {
+    pub fn new(input: &'a InputFile) -> Self {
+        InputCursor { input: input, position: 0 }
+    }
+}
+
+impl BinaryStream for InputCursor<'_> {
+    fn read_bytes(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        self.input.read_at(self.position, buf)?;
+        self.position += buf.len() as u64;
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, _buf: &[u8]) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Other, "Input files are read only"))
+    }
+
+    fn seek(&mut self, pos: u64) -> io::Result<()> {
+        self.position = pos;
+        Ok(())
+    }
+
+    fn position(&mut self) -> io::Result<u64> {
+        Ok(self.position)
+    }
+
+    fn length(&mut self) -> io::Result<u64> {
+        self.input.length()
+    }
+
+    fn copy_data<T : BinaryStream>(&mut self, output_file : &mut T, size : usize) -> io::Result<()> {
+        if let Some(data) = self.input.mapped_slice(self.position, size) {
+            output_file.write_bytes(data?)?;
+            self.position += size as u64;
+            return Ok(());
+        }
+
+        const BUFFER_SIZE: usize = 0x40000;
+        let mut buffer = vec![0u8; std::cmp::min(size, BUFFER_SIZE)];
+        let mut bytes_left = size;
+        while bytes_left > 0 {
+            let bytes_to_read = std::cmp::min(bytes_left, BUFFER_SIZE);
+            self.read_bytes(&mut buffer[..bytes_to_read])?;
+            output_file.write_bytes(&buffer[..bytes_to_read])?;
+            bytes_left -= bytes_to_read;
+        }
+        Ok(())
+    }
+}
+
+/// Decompressed size of the chunks we write
+pub const COMPRESSED_CHUNK_SIZE: usize = 0x20000;
+
+/// Compressed chunk waiting in the spill file
+#[derive(Debug, Clone, Copy)]
+struct SpilledChunk {
+    offset: u64,
+    compressed_size: u64,
+    size: u32
+}
+
+/// Writes a compressed pak in the layout read by `CompressedFile`
+///
+/// Chunks are compressed as soon as the write cursor leaves them and kept in a temporary
+/// spill file, seeking back into a chunk decompresses it again so the offset tables
+/// `ResourceNode::write` patches after the fact still work. Call `finish` to write the
+/// chunk table and the chunks to the output.
+pub struct CompressedWriter<T: BinaryStream> {
+    output: T,
+    level: flate2::Compression,
+
+    spill: UncompressedFile,
+    spill_length: u64,
+    chunks: Vec<Option<SpilledChunk>>,
+
+    current_index: usize,
+    current: Vec<u8>,
+    current_dirty: bool,
+
+    position: u64,
+    length: u64
+}
+
+impl<T: BinaryStream> CompressedWriter<T> {
+    /// Level is the zlib compression level, 0-9
+    pub fn new(output: T, level: u32) -> io::Result<Self> {
+        Ok(CompressedWriter {
+            output: output,
+            level: flate2::Compression::new(level),
+            spill: UncompressedFile::new(tempfile::tempfile()?),
+            spill_length: 0,
+            chunks: Vec::new(),
+            current_index: 0,
+            current: Vec::new(),
+            current_dirty: false,
+            position: 0,
+            length: 0
+        })
+    }
+
+    /// Compresses the current chunk into the spill file if it was modified
+    fn flush_chunk(&mut self) -> io::Result<()> {
+        if !self.current_dirty {
+            return Ok(());
+        }
+
+        let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), self.level);
+        encoder.write_all(&self.current)?;
+        let compressed = encoder.finish()?;
+
+        self.spill.seek(self.spill_length)?;
+        self.spill.write_bytes(&compressed)?;
+
+        if self.chunks.len() <= self.current_index {
+            self.chunks.resize(self.current_index + 1, None);
+        }
+        self.chunks[self.current_index] = Some(SpilledChunk {
+            offset: self.spill_length,
+            compressed_size: compressed.len() as u64,
+            size: self.current.len() as u32
+        });
+        self.spill_length += compressed.len() as u64;
+        self.current_dirty = false;
+        Ok(())
+    }
+
+    /// Makes `chunk_index` the current chunk, decompressing it if was already written
+    fn switch_chunk(&mut self, chunk_index: usize) -> io::Result<()> {
+        if chunk_index == self.current_index && (self.current_dirty || !self.current.is_empty()) {
+            return Ok(());
+        }
+        self.flush_chunk()?;
+
+        self.current.clear();
+        if let Some(Some(chunk)) = self.chunks.get(chunk_index) {
+            self.spill.seek(chunk.offset)?;
+            let compressed = self.spill.read_vector(chunk.compressed_size as usize)?;
+            self.current.resize(chunk.size as usize, 0);
+            flate2::read::ZlibDecoder::new(&compressed[..]).read_exact(&mut self.current)?;
+        }
+        self.current_index = chunk_index;
+        Ok(())
+    }
+
+    /// Writes the chunk table and compressed chunks to the output, returning it. Containers
+    /// need at least one chunk, so finishing without writing anything fails.
+    pub fn finish(mut self) -> io::Result<T> {
+        if self.length == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Nothing was written to the compressed stream"));
+        }
+        self.flush_chunk()?;
+
+        // every chunk but the last one has to be full size
+        let chunk_count = (self.length as usize + COMPRESSED_CHUNK_SIZE - 1) / COMPRESSED_CHUNK_SIZE;
+        for chunk_index in 0..chunk_count {
+            let expected_size = std::cmp::min(COMPRESSED_CHUNK_SIZE as u64, self.length - (chunk_index * COMPRESSED_CHUNK_SIZE) as u64) as usize;
+            let size = match self.chunks.get(chunk_index) {
+                Some(Some(chunk)) => chunk.size as usize,
+                _ => 0
+            };
+            if size != expected_size {
+                self.switch_chunk(chunk_index)?;
+                self.current.resize(expected_size, 0);
+                self.current_dirty = true;
+                self.flush_chunk()?;
+            }
+        }
+
+        self.output.write_u32(chunk_count as u32)?;
+        let mut chunk_offset = 4 + chunk_count as u64 * 8;
+        for chunk in &self.chunks[..chunk_count] {
+            self.output.write_u64(chunk_offset)?;
+            chunk_offset += 4 + chunk.unwrap().compressed_size;
+        }
+
+        for chunk in &self.chunks[..chunk_count] {
+            let chunk = chunk.unwrap();
+            self.output.write_u32(chunk.size)?;
+            self.spill.seek(chunk.offset)?;
+            self.spill.copy_data(&mut self.output, chunk.compressed_size as usize)?;
+        }
+
+        Ok(self.output)
+    }
+}
+
+impl<T: BinaryStream> BinaryStream for CompressedWriter<T> {
+    fn read_bytes(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        if self.position + buf.len() as u64 > self.length {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Read past end of compressed stream"));
+        }
+
+        let mut bytes_read = 0;
+        while bytes_read < buf.len() {
+            self.switch_chunk(self.position as usize / COMPRESSED_CHUNK_SIZE)?;
+            let chunk_offset = self.position as usize % COMPRESSED_CHUNK_SIZE;
+            let bytes_to_copy = std::cmp::min(buf.len() - bytes_read, COMPRESSED_CHUNK_SIZE - chunk_offset);
+
+            // anything we skipped over reads back as zero
+            if self.current.len() < chunk_offset + bytes_to_copy {
+                self.current.resize(chunk_offset + bytes_to_copy, 0);
+            }
+            buf[bytes_read..bytes_read + bytes_to_copy].copy_from_slice(&self.current[chunk_offset..chunk_offset + bytes_to_copy]);
+
+            bytes_read += bytes_to_copy;
+            self.position += bytes_to_copy as u64;
+        }
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, buf: &[u8]) -> io::Result<()> {
+        let mut bytes_written = 0;
+        while bytes_written < buf.len() {
+            self.switch_chunk(self.position as usize / COMPRESSED_CHUNK_SIZE)?;
+            let chunk_offset = self.position as usize % COMPRESSED_CHUNK_SIZE;
+            let bytes_to_copy = std::cmp::min(buf.len() - bytes_written, COMPRESSED_CHUNK_SIZE - chunk_offset);
+
+            if self.current.len() < chunk_offset + bytes_to_copy {
+                self.current.resize(chunk_offset + bytes_to_copy, 0);
+            }
+            self.current[chunk_offset..chunk_offset + bytes_to_copy].copy_from_slice(&buf[bytes_written..bytes_written + bytes_to_copy]);
+            self.current_dirty = true;
+
+            bytes_written += bytes_to_copy;
+            self.position += bytes_to_copy as u64;
+        }
+        self.length = std::cmp::max(self.length, self.position);
+        Ok(())
+    }
+
+    fn seek(&mut self, pos: u64) -> io::Result<()> {
+        self.position = pos;
+        Ok(())
+    }
+
+    fn position(&mut self) -> io::Result<u64> {
+        Ok(self.position)
+    }
+
+    fn length(&mut self) -> io::Result<u64> {
+        Ok(self.length)
+    }
+}
@@ -0,0 +1,106 @@
+use std::{io, path::{Path, PathBuf}};
+use crate::util::join_node_path;
+use super::{PakInterface, PakResult, ResourceNode, ResourceType, write_input};
+
+/// Path the previous version of a pak is kept under
+fn backup_path(path: &Path) -> PathBuf {
+    let mut backup_path = path.as_os_str().to_owned();
+    backup_path.push(".bak");
+    PathBuf::from(backup_path)
+}
+
+fn verify_failed(path: &str, message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Saved pak doesn't match, \"{}\" {}", path, message))
+}
+
+/// Checks that the tree read back from a saved pak is the tree that was saved
+pub(super) fn verify_node(expected: &ResourceNode, saved: &ResourceNode, node_path: &str) -> io::Result<()> {
+    if expected.header.class != saved.header.class || expected.header.uuid != saved.header.uuid {
+        return Err(verify_failed(node_path, "has a different header"));
+    }
+    // nodes that were never read are copied as they are
+    if !expected.is_loaded() {
+        return Ok(());
+    }
+    if let Err(error) = saved.load() {
+        return Err(verify_failed(node_path, &format!("can't be read back, {}", error)));
+    }
+    if expected.children().len() != saved.children().len() {
+        return Err(verify_failed(node_path, &format!("has {} children instead of {}", saved.children().len(), expected.children().len())));
+    }
+
+    for (expected_child, saved_child) in expected.children().iter().zip(saved.children()) {
+        let path = join_node_path(node_path, &expected_child.name);
+        if expected_child.name != saved_child.name {
+            return Err(verify_failed(&path, &format!("was saved as \"{}\"", saved_child.name)));
+        }
+
+        match (&expected_child.contents, &saved_child.contents) {
+            (ResourceType::Node(expected_node), ResourceType::Node(saved_node)) => verify_node(expected_node, saved_node, &path)?,
+            (ResourceType::Link(expected_index), ResourceType::Link(saved_index)) if expected_index == saved_index => {},
+            (ResourceType::Data, ResourceType::Data) if expected_child.size == saved_child.size => {},
+            (ResourceType::Resource(expected_header), ResourceType::Resource(saved_header))
+                if expected_header.class == saved_header.class && expected_header.uuid == saved_header.uuid && expected_child.size == saved_child.size => {},
+            _ => return Err(verify_failed(&path, "was saved with different contents"))
+        }
+
+        // rewritten data is compared in full, copied data only by size
+        if let Some(data) = &expected_child.new_data {
+            let same_data = saved_child.with_data(|saved_data| saved_data == &data[..])
+                .map_err(|error| verify_failed(&path, &format!("can't be read back, {}", error)))?;
+            if !same_data {
+                return Err(verify_failed(&path, "was saved with different data"));
+            }
+        }
+    }
+    Ok(())
+}
+
+impl PakInterface {
+    /// Saves to a temporary file next to `path`, checks that it reads back as the same tree and then
+    /// renames it over `path`, so the target is never left half written. This works on the file the
+    /// pak was opened from as well. Afterwards the pak refers to the saved file.
+    /// Returns where the previous version was kept, if `keep_backup` is set and there was one.
+    pub fn save_as(&mut self, path: &Path, compression_level: Option<u32>, keep_backup: bool) -> PakResult<Option<PathBuf>> {
+        let directory = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new(".")
+        };
+        let temp_file = tempfile::Builder::new().prefix(".pakedit").suffix(".tmp").tempfile_in(directory)?;
+
+        match compression_level {
+            Some(level) => self.save_compressed(temp_file.reopen()?, level)?,
+            None => self.save(temp_file.reopen()?)?
+        }
+        temp_file.as_file().sync_all()?;
+
+        let saved = PakInterface::open(temp_file.reopen()?)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, format!("Saved pak can't be read back, {}", error)))?;
+        verify_node(&self.root_node, &saved.root_node, "")?;
+
+        if let Ok(metadata) = std::fs::metadata(path) {
+            std::fs::set_permissions(temp_file.path(), metadata.permissions())?;
+        }
+
+        let mut kept_backup = None;
+        if keep_backup && path.exists() {
+            let backup_path = backup_path(path);
+            if backup_path.exists() {
+                std::fs::remove_file(&backup_path)?;
+            }
+            // a hard link keeps the original in place until the rename, copy where they aren't supported
+            if std::fs::hard_link(path, &backup_path).is_err() {
+                std::fs::copy(path, &backup_path)?;
+            }
+            kept_backup = Some(backup_path);
+        }
+
+        // a mapped pak can't be replaced on Windows
+        if let Some(input) = &self.input_file {
+            write_input(input).unmap()?;
+        }
+        temp_file.persist(path).map_err(|error| error.error)?;
+        *self = saved;
+        Ok(kept_backup)
+    }
+}
@@ -0,0 +1,1379 @@
+//! Round trip tests, the paks are laid out by hand here so they don't depend on the writer
+
+use std::{convert::TryInto, fs::File, io::{Read, Seek, SeekFrom, Write}};
+use super::*;
+
+pub struct TestMetaData(pub u32, pub u128, pub Vec<u8>);
+
+pub struct TestHeader {
+    pub class: &'static str,
+    pub uuid: u128,
+    pub unk0: u32,
+    pub meta_data: Vec<TestMetaData>
+}
+
+pub enum TestEntry {
+    Data(Vec<u8>),
+    Resource(TestHeader, Vec<u8>),
+    Node(TestNode),
+    Link(usize)
+}
+
+pub struct TestNode {
+    pub header: TestHeader,
+    pub children: Vec<(String, TestEntry)>
+}
+
+pub fn header(class: &'static str, uuid: u128) -> TestHeader {
+    TestHeader { class: class, uuid: uuid, unk0: 0, meta_data: Vec::new() }
+}
+
+/// Data that can't be mistaken for a header
+pub fn test_data(seed: u8, len: usize) -> Vec<u8> {
+    (0..len).map(|index| (index as u8).wrapping_mul(7).wrapping_add(seed) | 0x80).collect()
+}
+
+pub fn encode_header(header: &TestHeader) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.extend(&RESOURCE_MAGIC.to_le_bytes());
+    let mut class = [0u8; 0x20];
+    class[..header.class.len()].copy_from_slice(header.class.as_bytes());
+    bytes.extend(&class);
+    bytes.extend(&header.uuid.to_le_bytes());
+    bytes.extend(&header.unk0.to_le_bytes());
+    bytes.extend(&(header.meta_data.len() as u32).to_le_bytes());
+
+    let mut meta_data: Vec<u8> = Vec::new();
+    for TestMetaData(unk0, unk1, name_data) in &header.meta_data {
+        meta_data.extend(&unk0.to_le_bytes());
+        meta_data.extend(&unk1.to_le_bytes());
+        meta_data.extend(&(name_data.len() as u32).to_le_bytes());
+        meta_data.extend(name_data);
+    }
+    bytes.extend(&(meta_data.len() as u32).to_le_bytes());
+    bytes.extend(meta_data);
+    bytes
+}
+
+pub fn encode_node(node: &TestNode) -> Vec<u8> {
+    let count = node.children.len();
+    let mut tables = encode_header(&node.header);
+    tables.push(1);
+    tables.extend(&0x1000000u32.to_le_bytes());
+    tables.extend(&(count as u32).to_le_bytes());
+    tables.extend(&4u32.to_le_bytes());
+    tables.push(1);
+    for (name, _) in &node.children {
+        tables.extend(&(name.len() as u32).to_le_bytes());
+        tables.extend(name.as_bytes());
+    }
+    tables.push(1);
+    let data_start = (tables.len() + count * 8 + 1 + count * 4 + 1 + count * 4) as u64;
+
+    // children are laid out in order, links share the range of their target
+    let mut data = Vec::new();
+    let mut ranges = vec![(0u64, 0u32); count];
+    for (index, (_, entry)) in node.children.iter().enumerate() {
+        let bytes = match entry {
+            TestEntry::Data(bytes) => bytes.clone(),
+            TestEntry::Resource(header, bytes) => [encode_header(header), bytes.clone()].concat(),
+            TestEntry::Node(child_node) => encode_node(child_node),
+            TestEntry::Link(_) => continue
+        };
+        ranges[index] = (data_start + data.len() as u64, bytes.len() as u32);
+        data.extend(bytes);
+    }
+    for (index, (_, entry)) in node.children.iter().enumerate() {
+        if let TestEntry::Link(target) = entry {
+            ranges[index] = ranges[*target];
+        }
+    }
+
+    for (offset, _) in &ranges {
+        tables.extend(&offset.to_le_bytes());
+    }
+    tables.push(1);
+    for (_, size) in &ranges {
+        tables.extend(&size.to_le_bytes());
+    }
+    tables.push(1);
+    for (_, entry) in &node.children {
+        let is_link = if let TestEntry::Link(_) = entry { 1u32 } else { 0 };
+        tables.extend(&is_link.to_le_bytes());
+    }
+    assert_eq!(tables.len() as u64, data_start);
+    [tables, data].concat()
+}
+
+/// A pak with every kind of child: nested nodes of each class, links, small entries
+/// and resources with and without metadata
+pub fn sample_node() -> TestNode {
+    let mut texture = header("texture", 0x1234_5678_9abc_def0_0fed_cba9_8765_4321);
+    texture.unk0 = 3;
+    texture.meta_data.push(TestMetaData(1, 0xaaaa_bbbb, b"first name".to_vec()));
+    texture.meta_data.push(TestMetaData(2, 0xcccc_dddd, Vec::new()));
+
+    let scripts = TestNode {
+        header: header("ssl_block", 2),
+        children: vec![
+            ("a.ssl".to_string(), TestEntry::Data(test_data(1, 500))),
+            ("b.ssl".to_string(), TestEntry::Data(test_data(2, 4000))),
+            ("<bitmap>\\b.bitmap".to_string(), TestEntry::Resource(header("bitmap", 7), test_data(3, 300))),
+            ("b link".to_string(), TestEntry::Link(1))
+        ]
+    };
+    let cache = TestNode {
+        header: header("cache_block", 3),
+        children: vec![
+            ("tiny".to_string(), TestEntry::Data(test_data(4, 5))),
+            ("empty".to_string(), TestEntry::Data(Vec::new()))
+        ]
+    };
+    let nested = TestNode {
+        header: header("pak", 4),
+        children: vec![
+            ("cache".to_string(), TestEntry::Node(cache)),
+            ("inner.bin".to_string(), TestEntry::Data(test_data(5, 200)))
+        ]
+    };
+
+    TestNode {
+        header: header("pak", 1),
+        children: vec![
+            ("data.bin".to_string(), TestEntry::Data(test_data(6, 11000))),
+            ("<texture>\\tex.tex".to_string(), TestEntry::Resource(texture, test_data(7, 5000))),
+            ("scripts.ssl_block".to_string(), TestEntry::Node(scripts)),
+            ("link".to_string(), TestEntry::Link(0)),
+            ("nested".to_string(), TestEntry::Node(nested)),
+            ("texture link".to_string(), TestEntry::Link(1))
+        ]
+    }
+}
+
+pub fn open_bytes(bytes: &[u8]) -> PakInterface {
+    let mut file = tempfile::tempfile().unwrap();
+    file.write_all(bytes).unwrap();
+    file.seek(SeekFrom::Start(0)).unwrap();
+    PakInterface::open(file).unwrap()
+}
+
+fn read_back(mut file: File) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    file.seek(SeekFrom::Start(0)).unwrap();
+    file.read_to_end(&mut bytes).unwrap();
+    bytes
+}
+
+pub fn save_bytes(pak: &mut PakInterface) -> Vec<u8> {
+    let file = tempfile::tempfile().unwrap();
+    pak.save(file.try_clone().unwrap()).unwrap();
+    read_back(file)
+}
+
+fn save_compressed_bytes(pak: &mut PakInterface, level: u32) -> Vec<u8> {
+    let file = tempfile::tempfile().unwrap();
+    pak.save_compressed(file.try_clone().unwrap(), level).unwrap();
+    read_back(file)
+}
+
+fn node_mut<'a>(pak: &'a mut PakInterface, path: &[&str]) -> &'a mut ResourceNode {
+    let mut node = pak.get_root_node_mut();
+    for name in path {
+        node = match node.find_child_mut(name).unwrap().contents_mut() {
+            ResourceType::Node(child_node) => child_node,
+            _ => panic!("\"{}\" isn't a node", name)
+        };
+    }
+    node
+}
+
+fn test_node_mut<'a>(node: &'a mut TestNode, path: &[&str]) -> &'a mut TestNode {
+    let mut node = node;
+    for name in path {
+        node = match node.children.iter_mut().find(|(child_name, _)| child_name == name) {
+            Some((_, TestEntry::Node(child_node))) => child_node,
+            _ => panic!("\"{}\" isn't a node", name)
+        };
+    }
+    node
+}
+
+fn set_test_data(node: &mut TestNode, name: &str, data: Vec<u8>) {
+    match node.children.iter_mut().find(|(child_name, _)| child_name == name) {
+        Some((_, TestEntry::Data(bytes))) | Some((_, TestEntry::Resource(_, bytes))) => *bytes = data,
+        _ => panic!("\"{}\" has no data", name)
+    }
+}
+
+#[test]
+fn open_reads_the_tree() {
+    let pak = open_bytes(&encode_node(&sample_node()));
+    let root = pak.get_root_node();
+    assert_eq!(root.children().len(), 6);
+    assert_eq!(root.children()[0].data().unwrap(), test_data(6, 11000));
+    assert_eq!(root.children()[1].data().unwrap(), test_data(7, 5000));
+    match root.children()[1].contents() {
+        ResourceType::Resource(header) => {
+            assert_eq!(header.class(), "texture");
+            assert_eq!(header.meta_data().len(), 2);
+            assert_eq!(header.meta_data()[0].name_data(), b"first name");
+        },
+        contents => panic!("texture read as {}", contents)
+    }
+    assert!(matches!(root.children()[3].contents(), ResourceType::Link(0)));
+    assert!(matches!(root.children()[5].contents(), ResourceType::Link(1)));
+    assert!(pak.validate().is_empty());
+}
+
+#[test]
+fn clean_save_is_byte_exact() {
+    let input = encode_node(&sample_node());
+    let mut pak = open_bytes(&input);
+    assert_eq!(save_bytes(&mut pak), input);
+    // saving again from the same input changes nothing
+    assert_eq!(save_bytes(&mut pak), input);
+}
+
+#[test]
+fn full_rewrite_with_the_same_data_is_byte_exact() {
+    let input = encode_node(&sample_node());
+    let mut pak = open_bytes(&input);
+    let data = pak.get_root_node().children()[0].data().unwrap();
+    pak.get_root_node_mut().children_mut()[0].set_data(data);
+    assert_eq!(save_bytes(&mut pak), input);
+}
+
+#[test]
+fn nested_rewrite_with_the_same_data_is_byte_exact() {
+    let input = encode_node(&sample_node());
+    let mut pak = open_bytes(&input);
+    let cache = node_mut(&mut pak, &["nested", "cache"]);
+    let data = cache.children()[0].data().unwrap();
+    cache.children_mut()[0].set_data(data);
+    assert_eq!(save_bytes(&mut pak), input);
+}
+
+#[test]
+fn replaced_data_matches_a_fresh_pak() {
+    let mut expected = sample_node();
+    set_test_data(&mut expected, "data.bin", test_data(10, 20000));
+    set_test_data(test_node_mut(&mut expected, &["scripts.ssl_block"]), "b.ssl", test_data(11, 10));
+
+    let mut pak = open_bytes(&encode_node(&sample_node()));
+    pak.get_root_node_mut().find_child_mut("data.bin").unwrap().set_data(test_data(10, 20000));
+    node_mut(&mut pak, &["scripts.ssl_block"]).find_child_mut("b.ssl").unwrap().set_data(test_data(11, 10));
+    let output = save_bytes(&mut pak);
+    assert_eq!(output, encode_node(&expected));
+
+    // links follow their target
+    let saved = open_bytes(&output);
+    assert_eq!(saved.get_root_node().children()[3].size(), saved.get_root_node().children()[0].size());
+}
+
+#[test]
+fn replaced_resource_matches_a_fresh_pak() {
+    let mut expected = sample_node();
+    set_test_data(test_node_mut(&mut expected, &["scripts.ssl_block"]), "<bitmap>\\b.bitmap", test_data(12, 1000));
+
+    let mut pak = open_bytes(&encode_node(&sample_node()));
+    node_mut(&mut pak, &["scripts.ssl_block"]).find_child_mut("<bitmap>\\b.bitmap").unwrap().set_data(test_data(12, 1000));
+    assert_eq!(save_bytes(&mut pak), encode_node(&expected));
+}
+
+#[test]
+fn renamed_child_only_rewrites_the_header() {
+    let mut expected = sample_node();
+    expected.children[0].0 = "a much longer name for data.bin".to_string();
+    test_node_mut(&mut expected, &["nested"]).children[1].0 = "i".to_string();
+
+    let mut pak = open_bytes(&encode_node(&sample_node()));
+    pak.get_root_node_mut().children_mut()[0].rename("a much longer name for data.bin".to_string());
+    node_mut(&mut pak, &["nested"]).children_mut()[1].rename("i".to_string());
+    pak.root_node.update_state();
+    assert_eq!(pak.root_node.state, NodeModifiedState::Full);
+    assert_eq!(node_mut(&mut pak, &["nested"]).state, NodeModifiedState::Header);
+    assert_eq!(save_bytes(&mut pak), encode_node(&expected));
+}
+
+#[test]
+fn children_inside_the_tables_survive_a_rename() {
+    let node = TestNode {
+        header: header("pak", 1),
+        children: vec![
+            ("empty".to_string(), TestEntry::Data(Vec::new())),
+            ("a long name".to_string(), TestEntry::Data(test_data(1, 100))),
+            ("in tables".to_string(), TestEntry::Data(test_data(2, 8)))
+        ]
+    };
+    let mut bytes = encode_node(&node);
+    patch_child(&mut bytes, 0, &node, 0, Some(0), None, None);
+    patch_child(&mut bytes, 0, &node, 2, Some(0x10), None, None);
+
+    let mut pak = open_bytes(&bytes);
+    pak.get_root_node_mut().children_mut()[1].rename("a".to_string());
+    pak.root_node.update_state();
+    assert_eq!(pak.root_node.state, NodeModifiedState::Header);
+    let saved = save_bytes(&mut pak);
+
+    let saved_pak = open_bytes(&saved);
+    let children = saved_pak.get_root_node().children();
+    assert_eq!((*children[0].offset(), *children[0].size()), (0, 0));
+    assert_eq!(children[1].name(), "a");
+    assert_eq!(children[1].data().unwrap(), test_data(1, 100));
+    assert_eq!(children[2].data().unwrap(), bytes[0x10..0x18].to_vec());
+}
+
+#[test]
+fn root_rename_keeps_data_in_place() {
+    let mut expected = sample_node();
+    expected.children[2].0 = "s".to_string();
+
+    let mut pak = open_bytes(&encode_node(&sample_node()));
+    pak.get_root_node_mut().children_mut()[2].rename("s".to_string());
+    pak.root_node.update_state();
+    assert_eq!(pak.root_node.state, NodeModifiedState::Header);
+    assert_eq!(save_bytes(&mut pak), encode_node(&expected));
+}
+
+#[test]
+fn added_children_are_appended() {
+    let mut expected = sample_node();
+    expected.children.push(("added.bin".to_string(), TestEntry::Data(test_data(13, 3000))));
+    expected.children.push(("added.bitmap".to_string(), TestEntry::Resource(header("bitmap", 9), test_data(14, 100))));
+    expected.children.push(("added node".to_string(), TestEntry::Node(TestNode {
+        header: header("ssl_block", 10),
+        children: vec![("added.ssl".to_string(), TestEntry::Data(test_data(15, 50)))]
+    })));
+
+    let mut pak = open_bytes(&encode_node(&sample_node()));
+    let root = pak.get_root_node_mut();
+    root.add_child("added.bin".to_string(), ResourceType::Data, test_data(13, 3000)).unwrap();
+    let bitmap = ResourceHeader::new("bitmap".to_string(), 9, 0, Vec::new());
+    root.add_child("added.bitmap".to_string(), ResourceType::Resource(bitmap), test_data(14, 100)).unwrap();
+    let node = ResourceNode::new(ResourceHeader::new("ssl_block".to_string(), 10, 0, Vec::new()));
+    let index = root.add_child("added node".to_string(), ResourceType::Node(node), Vec::new()).unwrap();
+    match root.children_mut()[index].contents_mut() {
+        ResourceType::Node(node) => node.add_child("added.ssl".to_string(), ResourceType::Data, test_data(15, 50)).unwrap(),
+        _ => unreachable!()
+    };
+
+    pak.root_node.update_state();
+    assert_eq!(pak.root_node.state, NodeModifiedState::Append);
+    assert_eq!(save_bytes(&mut pak), encode_node(&expected));
+}
+
+#[test]
+fn removed_and_moved_children_match_a_fresh_pak() {
+    let mut expected = sample_node();
+    expected.children.remove(3);
+    let moved = expected.children.remove(0);
+    expected.children.insert(2, moved);
+    // "texture link" follows its target to the front
+    expected.children[4].1 = TestEntry::Link(0);
+
+    let mut pak = open_bytes(&encode_node(&sample_node()));
+    let root = pak.get_root_node_mut();
+    root.remove_child(3, false).unwrap();
+    root.move_child(0, 2).unwrap();
+    assert_eq!(save_bytes(&mut pak), encode_node(&expected));
+}
+
+#[test]
+fn compressed_round_trip_is_byte_exact() {
+    let mut node = sample_node();
+    // spans several compressed chunks
+    node.children.push(("big.bin".to_string(), TestEntry::Data(test_data(16, 3 * pak_io::COMPRESSED_CHUNK_SIZE + 123))));
+    let input = encode_node(&node);
+
+    for level in &[0, 6] {
+        let mut pak = open_bytes(&input);
+        let compressed = save_compressed_bytes(&mut pak, *level);
+        assert_ne!(compressed[..4], input[..4]);
+        let mut decompressed = open_bytes(&compressed);
+        assert_eq!(decompressed.get_root_node().children()[6].data().unwrap(), test_data(16, 3 * pak_io::COMPRESSED_CHUNK_SIZE + 123));
+        assert_eq!(save_bytes(&mut decompressed), input);
+    }
+}
+
+#[test]
+fn built_tree_matches_a_fresh_pak() {
+    let expected = TestNode {
+        header: header("pak", 20),
+        children: vec![
+            ("data".to_string(), TestEntry::Data(test_data(17, 100))),
+            ("resource".to_string(), TestEntry::Resource(header("bitmap", 21), test_data(18, 200))),
+            ("node".to_string(), TestEntry::Node(TestNode {
+                header: header("cache_block", 22),
+                children: vec![("inner".to_string(), TestEntry::Data(test_data(19, 10)))]
+            })),
+            ("link".to_string(), TestEntry::Link(1))
+        ]
+    };
+
+    let mut builder = NodeBuilder::new(ResourceHeader::new("pak".to_string(), 20, 0, Vec::new()));
+    builder.add_data("data".to_string(), test_data(17, 100));
+    builder.add_resource("resource".to_string(), ResourceHeader::new("bitmap".to_string(), 21, 0, Vec::new()), test_data(18, 200));
+    let mut child_builder = NodeBuilder::new(ResourceHeader::new("cache_block".to_string(), 22, 0, Vec::new()));
+    child_builder.add_data("inner".to_string(), test_data(19, 10));
+    builder.add_node("node".to_string(), child_builder).unwrap();
+    builder.add_link("link".to_string(), 1);
+
+    let mut pak = PakInterface::from_root_node(builder.build().unwrap());
+    assert_eq!(save_bytes(&mut pak), encode_node(&expected));
+}
+
+#[test]
+fn replaced_resource_with_meta_data_matches_a_fresh_pak() {
+    let mut expected = sample_node();
+    set_test_data(&mut expected, "<texture>\\tex.tex", test_data(22, 700));
+
+    let mut pak = open_bytes(&encode_node(&sample_node()));
+    pak.get_root_node_mut().find_child_mut("<texture>\\tex.tex").unwrap().set_data(test_data(22, 700));
+    assert_eq!(save_bytes(&mut pak), encode_node(&expected));
+}
+
+/* Header serialisation properties, checked against random headers from fixed seeds */
+
+const HEADER_SEEDS: u64 = 500;
+
+fn random_header(rng: &mut fastrand::Rng) -> (TestHeader, ResourceHeader) {
+    // the test header wants a static class, there are only so many of them
+    const CLASSES: [&str; 6] = ["bitmap", "texture", "x", "render_model_definition", "a_class_that_is_31_bytes_long__", ""];
+    let class = CLASSES[rng.usize(..CLASSES.len())];
+    let uuid = rng.u128(..);
+    let unk0 = rng.u32(..);
+
+    let mut meta_data = Vec::new();
+    for _ in 0..rng.usize(..6) {
+        let mut name_data = vec![0u8; rng.usize(..70)];
+        rng.fill(&mut name_data);
+        meta_data.push(TestMetaData(rng.u32(..), rng.u128(..), name_data));
+    }
+
+    let header = ResourceHeader::new(class.to_string(), uuid, unk0,
+        meta_data.iter().map(|TestMetaData(unk0, unk1, name_data)| MetaData::new(*unk0, *unk1, name_data.clone())).collect());
+    (TestHeader { class: class, uuid: uuid, unk0: unk0, meta_data: meta_data }, header)
+}
+
+fn write_header(header: &ResourceHeader) -> Vec<u8> {
+    let file = tempfile::tempfile().unwrap();
+    let mut stream = UncompressedFile::new(file.try_clone().unwrap());
+    header.write(&mut stream).unwrap();
+    read_back(file)
+}
+
+fn read_header(bytes: &[u8]) -> ResourceHeader {
+    let mut file = tempfile::tempfile().unwrap();
+    file.write_all(bytes).unwrap();
+    file.seek(SeekFrom::Start(0)).unwrap();
+    let mut stream = UncompressedFile::new(file);
+    let header = ResourceHeader::read(&mut stream).unwrap().unwrap();
+    assert_eq!(stream.position().unwrap(), bytes.len() as u64);
+    header
+}
+
+fn assert_same_header(left: &ResourceHeader, right: &ResourceHeader) {
+    assert_eq!(left.class, right.class);
+    assert_eq!(left.uuid, right.uuid);
+    assert_eq!(left.unk0, right.unk0);
+    assert_eq!(left.size, right.size);
+    assert_eq!(left.meta_data.len(), right.meta_data.len());
+    for (left, right) in left.meta_data.iter().zip(&right.meta_data) {
+        assert_eq!((left.unk0, left.unk1, &left.name_data), (right.unk0, right.unk1, &right.name_data));
+    }
+}
+
+#[test]
+fn written_header_matches_the_format() {
+    for seed in 0..HEADER_SEEDS {
+        let (expected, header) = random_header(&mut fastrand::Rng::with_seed(seed));
+        let bytes = write_header(&header);
+        assert_eq!(bytes, encode_header(&expected), "seed {}", seed);
+        assert_eq!(bytes.len() as u32, header.size, "seed {}", seed);
+    }
+}
+
+#[test]
+fn header_round_trips() {
+    for seed in 0..HEADER_SEEDS {
+        let (expected, header) = random_header(&mut fastrand::Rng::with_seed(seed));
+        assert_same_header(&read_header(&write_header(&header)), &header);
+        // and the other way around, starting from bytes laid out by hand
+        let bytes = encode_header(&expected);
+        assert_eq!(write_header(&read_header(&bytes)), bytes, "seed {}", seed);
+    }
+}
+
+#[test]
+fn rewritten_resources_round_trip() {
+    for seed in 0..50 {
+        let mut rng = fastrand::Rng::with_seed(seed);
+        let mut node = TestNode { header: header("pak", seed as u128), children: Vec::new() };
+        for index in 0..rng.usize(1..8) {
+            let (resource_header, _) = random_header(&mut rng);
+            if NODE_CLASSES.contains(&resource_header.class) || resource_header.class.is_empty() {
+                continue;
+            }
+            node.children.push((format!("resource {}", index), TestEntry::Resource(resource_header, test_data(index as u8, rng.usize(..300)))));
+        }
+        let input = encode_node(&node);
+
+        // rewriting every resource with its own data goes through ResourceHeader::write
+        let mut pak = open_bytes(&input);
+        for child in pak.get_root_node_mut().children_mut() {
+            let data = child.data().unwrap();
+            child.set_data(data);
+        }
+        assert_eq!(save_bytes(&mut pak), input, "seed {}", seed);
+    }
+}
+
+#[test]
+fn meta_data_names_are_decoded() {
+    let decoded: Vec<MetaDataName> = [&b"dep_one"[..], b"dep_two\0", b"", b"\0", b"bad\0name", &[0xff, 0x00]]
+        .iter().map(|data| MetaDataName::decode(data)).collect();
+    assert_eq!(decoded, vec![
+        MetaDataName::Text { name: "dep_one".to_string(), nul_terminated: false },
+        MetaDataName::Text { name: "dep_two".to_string(), nul_terminated: true },
+        MetaDataName::Text { name: String::new(), nul_terminated: false },
+        MetaDataName::Text { name: String::new(), nul_terminated: true },
+        MetaDataName::Raw(b"bad\0name".to_vec()),
+        MetaDataName::Raw(vec![0xff, 0x00])
+    ]);
+
+    let mut entry = MetaData::new(1, 2, b"dep_two\0".to_vec());
+    entry.rename("another dependency");
+    assert_eq!(entry.name_data(), b"another dependency\0");
+    assert_eq!(MetaData::dependency(1, 2, "dep").name_data(), b"dep");
+}
+
+#[test]
+fn meta_data_names_round_trip() {
+    for seed in 0..HEADER_SEEDS {
+        let mut rng = fastrand::Rng::with_seed(seed);
+        let mut data = vec![0u8; rng.usize(..40)];
+        // mostly text so both variants come up
+        for byte in &mut data {
+            *byte = if rng.u8(..8) == 0 { rng.u8(..) } else { rng.u8(0x20..0x7f) };
+        }
+        assert_eq!(MetaDataName::decode(&data).encode(), data, "seed {}", seed);
+    }
+}
+
+#[test]
+fn edited_resource_header_matches_a_fresh_pak() {
+    let mut expected = sample_node();
+    match &mut expected.children[1].1 {
+        TestEntry::Resource(header, _) => {
+            header.uuid = 99;
+            header.meta_data.remove(0);
+            header.meta_data.push(TestMetaData(5, 6, b"a new dependency".to_vec()));
+        },
+        _ => unreachable!()
+    }
+
+    let mut pak = open_bytes(&encode_node(&sample_node()));
+    pak.get_root_node_mut().children_mut()[1].edit_header(|header| {
+        header.set_uuid(99);
+        header.remove_meta_data(0);
+        header.add_meta_data(MetaData::dependency(5, 6, "a new dependency"));
+    }).unwrap();
+    assert_eq!(pak.get_root_node().children()[1].data().unwrap(), test_data(7, 5000));
+    assert_eq!(save_bytes(&mut pak), encode_node(&expected));
+}
+
+#[test]
+fn edited_node_header_only_rewrites_the_header() {
+    let mut expected = sample_node();
+    test_node_mut(&mut expected, &["nested"]).header.uuid = 0xabcdef;
+    test_node_mut(&mut expected, &["nested"]).header.class = "cache_block";
+
+    let mut pak = open_bytes(&encode_node(&sample_node()));
+    pak.get_root_node_mut().find_child_mut("nested").unwrap().edit_header(|header| {
+        header.set_uuid(0xabcdef);
+        header.set_class("cache_block".to_string());
+    }).unwrap();
+    pak.root_node.update_state();
+    assert_eq!(node_mut(&mut pak, &["nested"]).state, NodeModifiedState::Header);
+    assert_eq!(save_bytes(&mut pak), encode_node(&expected));
+}
+
+#[test]
+fn headers_edited_through_contents_are_saved() {
+    let mut expected = sample_node();
+    match &mut expected.children[1].1 {
+        TestEntry::Resource(header, _) => { header.meta_data.remove(0); },
+        _ => unreachable!()
+    }
+    test_node_mut(&mut expected, &["nested"]).header.uuid = 0xabcdef;
+
+    let mut pak = open_bytes(&encode_node(&sample_node()));
+    let root = pak.get_root_node_mut();
+    match root.children_mut()[1].contents_mut() {
+        ResourceType::Resource(header) => { header.remove_meta_data(0); },
+        _ => unreachable!()
+    }
+    match root.find_child_mut("nested").unwrap().contents_mut() {
+        ResourceType::Node(node) => node.header.set_uuid(0xabcdef),
+        _ => unreachable!()
+    }
+    // the data is still read from after the header in the file
+    assert_eq!(pak.get_root_node().children()[1].data().unwrap(), test_data(7, 5000));
+    let saved = save_bytes(&mut pak);
+    assert_eq!(saved, encode_node(&expected));
+    assert_eq!(open_bytes(&saved).get_root_node().children()[1].data().unwrap(), test_data(7, 5000));
+
+    // a header that got shorter is patched in place
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    file.write_all(&encode_node(&sample_node())).unwrap();
+    let mut pak = PakInterface::open(file.reopen().unwrap()).unwrap();
+    match pak.get_root_node_mut().children_mut()[1].contents_mut() {
+        ResourceType::Resource(header) => { header.remove_meta_data(0); },
+        _ => unreachable!()
+    }
+    pak.save_in_place(file.path()).unwrap();
+    pak.root_node.update_state();
+    assert_eq!(pak.root_node.state, NodeModifiedState::Clean);
+    assert_eq!(pak.get_root_node().children()[1].data().unwrap(), test_data(7, 5000));
+
+    let saved = PakInterface::open(file.reopen().unwrap()).unwrap();
+    let texture = &saved.get_root_node().children()[1];
+    match texture.contents() {
+        ResourceType::Resource(header) => assert_eq!(header.meta_data().len(), 1),
+        _ => unreachable!()
+    }
+    assert_eq!(texture.data().unwrap(), test_data(7, 5000));
+    assert_eq!(saved.get_root_node().children()[5].size(), texture.size());
+}
+
+#[test]
+fn invalid_header_edits_are_undone() {
+    let input = encode_node(&sample_node());
+    let mut pak = open_bytes(&input);
+    let root = pak.get_root_node_mut();
+    // a resource can't become a node or the other way around
+    assert!(root.children_mut()[1].edit_header(|header| header.set_class("pak".to_string())).is_err());
+    assert!(root.children_mut()[2].edit_header(|header| header.set_class("bitmap".to_string())).is_err());
+    assert!(root.children_mut()[0].edit_header(|header| header.set_uuid(1)).is_err());
+    match root.children()[2].contents() {
+        ResourceType::Node(node) => assert_eq!(node.header().class(), "ssl_block"),
+        _ => unreachable!()
+    }
+    assert_eq!(save_bytes(&mut pak), input);
+}
+
+#[test]
+fn guids_use_the_mixed_endian_layout() {
+    let guid = crate::util::Guid(u128::from_le_bytes([0x33, 0x22, 0x11, 0x00, 0x55, 0x44, 0x77, 0x66, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff]));
+    assert_eq!(guid.to_string(), "00112233-4455-6677-8899-aabbccddeeff");
+    assert_eq!("{00112233-4455-6677-8899-AABBCCDDEEFF}".parse(), Ok(guid));
+    assert!("00112233-4455-6677-8899-aabbccddeef".parse::<crate::util::Guid>().is_err());
+    assert!("0011223g-4455-6677-8899-aabbccddeeff".parse::<crate::util::Guid>().is_err());
+
+    let mut rng = fastrand::Rng::with_seed(20);
+    for _ in 0..HEADER_SEEDS {
+        let guid = crate::util::Guid(rng.u128(..));
+        assert_eq!(crate::util::parse_uuid(&guid.to_string()), Some(guid.0));
+    }
+    // the digits alone would be read in another byte order, so only the GUID form is accepted
+    assert_eq!(crate::util::parse_uuid("00112233445566778899aabbccddeeff"), None);
+    assert_eq!(crate::util::parse_uuid("0x1234"), None);
+}
+
+#[test]
+fn entries_are_found_by_uuid() {
+    let mut pak = open_bytes(&encode_node(&sample_node()));
+    let paths = |matches: Vec<UuidMatch>| matches.into_iter().map(|entry| (entry.path, entry.class)).collect::<Vec<_>>();
+    assert_eq!(paths(pak.find_by_uuid(1)), [(String::new(), "pak".to_string())]);
+    assert_eq!(paths(pak.find_by_uuid(7)), [("scripts.ssl_block/<bitmap>\\b.bitmap".to_string(), "bitmap".to_string())]);
+    assert_eq!(paths(pak.find_by_uuid(3)), [("nested/cache".to_string(), "cache_block".to_string())]);
+    assert!(pak.find_by_uuid(0x1234_5678_9abc_def0_0fed_cba9_8765_4321).len() == 1);
+    assert!(pak.find_by_uuid(99).is_empty());
+
+    // edits through the root node drop the index
+    pak.get_root_node_mut().children_mut()[1].edit_header(|header| header.set_uuid(99)).unwrap();
+    assert_eq!(paths(pak.find_by_uuid(99)), [("<texture>\\tex.tex".to_string(), "texture".to_string())]);
+}
+
+#[test]
+fn mapped_pak_reads_data_appended_in_place() {
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    file.write_all(&encode_node(&sample_node())).unwrap();
+    let mut pak = PakInterface::open(file.reopen().unwrap()).unwrap();
+    assert!(matches!(&*read_input(pak.input().unwrap()), pak_io::InputFile::Mapped(_)));
+    assert_eq!(pak.get_root_node().children()[1].with_data(|data| data.to_vec()).unwrap(), test_data(7, 5000));
+
+    // too big for its old place, so it moves past the end of the map
+    pak.get_root_node_mut().children_mut()[0].set_data(test_data(8, 20000));
+    pak.save_in_place(file.path()).unwrap();
+    assert!(matches!(&*read_input(pak.input().unwrap()), pak_io::InputFile::Mapped(_)));
+    let root = pak.get_root_node();
+    assert_eq!(root.children()[0].data().unwrap(), test_data(8, 20000));
+    assert_eq!(*root.children()[3].size(), 20000);
+
+    // the tree matches the patched file, nothing is written again
+    pak.root_node.update_state();
+    assert_eq!(pak.root_node.state, NodeModifiedState::Clean);
+    assert_eq!(save_bytes(&mut pak), std::fs::read(file.path()).unwrap());
+}
+
+#[test]
+fn nested_entries_are_patched_in_place_only_if_they_fit() {
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    file.write_all(&encode_node(&sample_node())).unwrap();
+    let mut pak = PakInterface::open(file.reopen().unwrap()).unwrap();
+
+    node_mut(&mut pak, &["scripts.ssl_block"]).find_child_mut("b.ssl").unwrap().set_data(test_data(20, 1000));
+    pak.save_in_place(file.path()).unwrap();
+    pak.root_node.update_state();
+    assert_eq!(pak.root_node.state, NodeModifiedState::Clean);
+
+    let saved = PakInterface::open(file.reopen().unwrap()).unwrap();
+    assert_eq!(find_path(saved.get_root_node(), "scripts.ssl_block/b.ssl").data().unwrap(), test_data(20, 1000));
+    assert_eq!(*find_path(saved.get_root_node(), "scripts.ssl_block/b link").size(), 1000);
+
+    // growing past the old slot would need the node to grow too
+    let patched = std::fs::read(file.path()).unwrap();
+    node_mut(&mut pak, &["scripts.ssl_block"]).find_child_mut("b.ssl").unwrap().set_data(test_data(21, 5000));
+    let error = pak.save_in_place(file.path()).unwrap_err();
+    assert!(error.to_string().contains("\"b.ssl\" no longer fits in its node, the pak needs a full save"));
+    assert_eq!(std::fs::read(file.path()).unwrap(), patched);
+    assert!(!in_place::journal_path(file.path()).exists());
+}
+
+#[test]
+fn interrupted_in_place_saves_are_rolled_back() {
+    let original = encode_node(&sample_node());
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    file.write_all(&original).unwrap();
+    let journal_path = in_place::journal_path(file.path());
+    let ranges = [(100, 2000), (20, 8), (original.len() as u64 - 4, 4)];
+
+    // the save got as far as patching some ranges and appending
+    let mut stream = UncompressedFile::new(file.reopen().unwrap());
+    in_place::write_journal(&mut stream, &journal_path, &ranges).unwrap();
+    for (position, length) in &ranges[..2] {
+        stream.seek(*position).unwrap();
+        stream.write_bytes(&vec![0xee; *length as usize]).unwrap();
+    }
+    stream.seek(original.len() as u64).unwrap();
+    stream.write_bytes(&test_data(22, 3000)).unwrap();
+
+    assert!(rollback_journal(file.path()).unwrap());
+    assert_eq!(std::fs::read(file.path()).unwrap(), original);
+    assert!(!journal_path.exists());
+    assert!(!rollback_journal(file.path()).unwrap());
+
+    // a journal that wasn't finished means the pak wasn't touched yet, it is dropped
+    in_place::write_journal(&mut stream, &journal_path, &ranges).unwrap();
+    let journal = std::fs::read(&journal_path).unwrap();
+    std::fs::write(&journal_path, &journal[..journal.len() - 2]).unwrap();
+    assert!(!rollback_journal(file.path()).unwrap());
+    assert_eq!(std::fs::read(file.path()).unwrap(), original);
+    assert!(!journal_path.exists());
+}
+
+#[test]
+fn nested_nodes_are_read_on_first_use() {
+    let pak = open_bytes(&encode_node(&sample_node()));
+    let root = pak.get_root_node();
+    let nested = match root.children()[4].contents() {
+        ResourceType::Node(node) => node,
+        _ => unreachable!()
+    };
+    assert!(!nested.is_loaded());
+    assert!(pak.validate_loaded().is_empty());
+    assert!(!nested.is_loaded());
+
+    assert_eq!(nested.children().len(), 2);
+    assert!(nested.is_loaded());
+    match nested.children()[0].contents() {
+        ResourceType::Node(cache) => assert!(!cache.is_loaded()),
+        _ => unreachable!()
+    }
+    assert_eq!(nested.children()[1].data().unwrap(), test_data(5, 200));
+}
+
+#[test]
+fn damaged_nested_nodes_are_copied_as_they_are() {
+    let mut input = encode_node(&sample_node());
+    let offset = *open_bytes(&input).get_root_node().children()[4].offset() as usize;
+    // the delimiter after the header of "nested"
+    input[offset + RESOURCE_HEADER_SIZE as usize] = 2;
+
+    let mut pak = open_bytes(&input);
+    let nested = match pak.get_root_node().children()[4].contents() {
+        ResourceType::Node(node) => node,
+        _ => unreachable!()
+    };
+    assert!(matches!(nested.load(), Err(PakError::BadDelimiter { found: 2, .. })));
+    assert!(nested.children().is_empty());
+    assert_eq!(pak.validate().iter().filter(|diagnostic| diagnostic.severity == Severity::Error).count(), 1);
+
+    pak.get_root_node_mut().children_mut()[0].set_data(test_data(9, 100));
+    let saved = save_bytes(&mut pak);
+    let mut expected = sample_node();
+    set_test_data(&mut expected, "data.bin", test_data(9, 100));
+    let mut expected = encode_node(&expected);
+    let offset = offset + expected.len() - input.len();
+    expected[offset + RESOURCE_HEADER_SIZE as usize] = 2;
+    assert_eq!(saved, expected);
+}
+
+fn assert_send_sync<T: Send + Sync>() {}
+
+/// Every Data and Resource child with its expected data, by node path
+fn expected_data(node: &TestNode, node_path: &str, entries: &mut Vec<(String, Vec<u8>)>) {
+    for (name, entry) in &node.children {
+        let path = crate::util::join_node_path(node_path, name);
+        match entry {
+            TestEntry::Data(data) | TestEntry::Resource(_, data) => entries.push((path, data.clone())),
+            TestEntry::Node(child_node) => expected_data(child_node, &path, entries),
+            TestEntry::Link(_) => {}
+        }
+    }
+}
+
+fn find_path<'a>(node: &'a ResourceNode, path: &str) -> &'a ResourceChild {
+    match path.split_once('/') {
+        Some((name, rest)) if node.find_child(path).is_none() => match node.find_child(name).unwrap().contents() {
+            ResourceType::Node(child_node) => find_path(child_node, rest),
+            _ => unreachable!()
+        },
+        _ => node.find_child(path).unwrap()
+    }
+}
+
+/// Reads every entry from several threads at once, nested nodes are read by whichever thread gets there first
+fn read_from_threads(pak: &PakInterface) {
+    let mut entries = Vec::new();
+    expected_data(&sample_node(), "", &mut entries);
+    std::thread::scope(|scope| {
+        for thread in 0..4 {
+            let entries = &entries;
+            scope.spawn(move || {
+                for round in 0..20 {
+                    for (path, data) in entries.iter().cycle().skip(thread + round).take(entries.len()) {
+                        assert_eq!(&find_path(pak.get_root_node(), path).data().unwrap(), data);
+                    }
+                }
+            });
+        }
+    });
+}
+
+#[test]
+fn paks_can_be_read_from_several_threads() {
+    assert_send_sync::<PakInterface>();
+    let input = encode_node(&sample_node());
+
+    let mapped = open_bytes(&input);
+    read_from_threads(&mapped);
+
+    let plain = open_bytes(&input);
+    write_input(plain.input().unwrap()).unmap().unwrap();
+    assert!(matches!(&*read_input(plain.input().unwrap()), pak_io::InputFile::Uncompressed(_)));
+    read_from_threads(&plain);
+
+    let compressed = open_bytes(&save_compressed_bytes(&mut open_bytes(&input), 6));
+    read_from_threads(&compressed);
+}
+
+#[test]
+fn parallel_extraction_matches_a_single_thread() {
+    let input = encode_node(&sample_node());
+    let pak = open_bytes(&input);
+
+    let single = tempfile::tempdir().unwrap();
+    let single_manifest = crate::dump::extract_all_parallel(pak.get_root_node(), single.path(), 1, |_, _| {}).unwrap();
+
+    let parallel = tempfile::tempdir().unwrap();
+    let reported = std::sync::Mutex::new(Vec::new());
+    let parallel_manifest = crate::dump::extract_all_parallel(pak.get_root_node(), parallel.path(), 4, |done, total| {
+        reported.lock().unwrap().push((done, total));
+    }).unwrap();
+
+    let mut entries = Vec::new();
+    expected_data(&sample_node(), "", &mut entries);
+    let expected_progress: Vec<_> = (1..=entries.len()).map(|done| (done, entries.len())).collect();
+    assert_eq!(reported.into_inner().unwrap(), expected_progress);
+
+    assert_eq!(serde_json::to_string(&parallel_manifest).unwrap(), serde_json::to_string(&single_manifest).unwrap());
+    let mut packed = crate::dump::pack(parallel.path()).unwrap();
+    assert_eq!(save_bytes(&mut packed), input);
+}
+
+#[test]
+fn paks_round_trip_through_memory() {
+    let input = encode_node(&sample_node());
+    let compressed = save_compressed_bytes(&mut open_bytes(&input), 6);
+
+    for bytes in &[&input, &compressed] {
+        let mut pak = PakInterface::open_from(MemoryStream::new(&bytes[..])).unwrap();
+        assert_eq!(pak.save_to(MemoryStream::new(Vec::new())).unwrap().into_inner(), input);
+
+        pak.get_root_node_mut().find_child_mut("data.bin").unwrap().set_data(test_data(10, 20000));
+        let mut expected = sample_node();
+        set_test_data(&mut expected, "data.bin", test_data(10, 20000));
+        assert_eq!(pak.save_to(MemoryStream::new(Vec::new())).unwrap().into_inner(), encode_node(&expected));
+        assert!(pak.save_in_place(std::path::Path::new("memory.pak")).is_err());
+    }
+
+    assert!(matches!(PakInterface::open_from(MemoryStream::new(&b"RES"[..])), Err(PakError::Io(_))));
+    assert!(matches!(PakInterface::open_from(MemoryStream::new(&[0xffu8; 64][..])), Err(PakError::BadMagic { .. })));
+}
+
+#[test]
+fn memory_streams_grow_like_files() {
+    let mut stream = MemoryStream::new(Vec::new());
+    stream.seek(4).unwrap();
+    stream.write_u32(0x01020304).unwrap();
+    stream.seek(2).unwrap();
+    stream.write_bytes(&[9, 9]).unwrap();
+    assert_eq!(stream.length().unwrap(), 8);
+    stream.seek(6).unwrap();
+    assert!(stream.read_u32().is_err());
+    assert_eq!(stream.into_inner(), vec![0, 0, 9, 9, 4, 3, 2, 1]);
+
+    assert!(MemoryStream::new(&[0u8; 4][..]).write_bytes(&[1]).is_err());
+}
+
+/// Position of the child offset table in an encoded node
+fn table_offset(node: &TestNode) -> usize {
+    let names: usize = node.children.iter().map(|(name, _)| 4 + name.len()).sum();
+    encode_header(&node.header).len() + 1 + 12 + 1 + names + 1
+}
+
+/// Overwrites the table entries of a child in a node encoded at `node_base`
+fn patch_child(bytes: &mut [u8], node_base: usize, node: &TestNode, index: usize, offset: Option<u64>, size: Option<u32>, is_link: Option<bool>) {
+    let count = node.children.len();
+    let tables = node_base + table_offset(node);
+    if let Some(offset) = offset {
+        bytes[tables + index * 8..][..8].copy_from_slice(&offset.to_le_bytes());
+    }
+    if let Some(size) = size {
+        bytes[tables + count * 8 + 1 + index * 4..][..4].copy_from_slice(&size.to_le_bytes());
+    }
+    if let Some(is_link) = is_link {
+        bytes[tables + count * 12 + 2 + index * 4..][..4].copy_from_slice(&(is_link as u32).to_le_bytes());
+    }
+}
+
+fn child_range(bytes: &[u8], node_base: usize, node: &TestNode, index: usize) -> (u64, u32) {
+    let count = node.children.len();
+    let tables = node_base + table_offset(node);
+    let offset = u64::from_le_bytes(bytes[tables + index * 8..][..8].try_into().unwrap());
+    let size = u32::from_le_bytes(bytes[tables + count * 8 + 1 + index * 4..][..4].try_into().unwrap());
+    (offset, size)
+}
+
+#[test]
+fn layout_problems_are_reported() {
+    let mut bitmap = header("bitmap", 7);
+    bitmap.meta_data.push(TestMetaData(1, 2, b"name".to_vec()));
+    let nested = TestNode { header: header("ssl_block", 2), children: vec![("x".to_string(), TestEntry::Data(test_data(1, 100)))] };
+    let node = TestNode {
+        header: header("pak", 1),
+        children: vec![
+            ("a".to_string(), TestEntry::Data(test_data(2, 100))),
+            ("b".to_string(), TestEntry::Data(test_data(3, 100))),
+            ("res".to_string(), TestEntry::Resource(bitmap, test_data(4, 50))),
+            ("l".to_string(), TestEntry::Link(0)),
+            ("n".to_string(), TestEntry::Node(nested)),
+            ("d".to_string(), TestEntry::Data(test_data(5, 100))),
+            ("far".to_string(), TestEntry::Data(test_data(6, 10)))
+        ]
+    };
+    let mut bytes = encode_node(&node);
+    let file_length = bytes.len() as u64;
+    let (a_offset, _) = child_range(&bytes, 0, &node, 0);
+    let (n_offset, _) = child_range(&bytes, 0, &node, 4);
+    let nested = match &node.children[4].1 { TestEntry::Node(nested) => nested, _ => unreachable!() };
+
+    patch_child(&mut bytes, 0, &node, 1, Some(a_offset + 40), None, None);
+    patch_child(&mut bytes, 0, &node, 2, None, Some(0x50), None);
+    patch_child(&mut bytes, 0, &node, 3, None, Some(50), None);
+    patch_child(&mut bytes, 0, &node, 5, None, None, Some(true));
+    patch_child(&mut bytes, 0, &node, 6, Some(file_length), None, None);
+    patch_child(&mut bytes, n_offset as usize, nested, 0, None, Some(150), None);
+
+    let pak = open_bytes(&bytes);
+    let mut diagnostics: Vec<_> = pak.validate().into_iter().map(|diagnostic| (diagnostic.path, diagnostic.severity, diagnostic.message)).collect();
+    diagnostics.sort();
+    let expected = [
+        ("b", Severity::Error, "overlaps \"a\" by 0x3c bytes"),
+        ("d", Severity::Error, "is a link to offset"),
+        ("far", Severity::Error, "past the end of the file"),
+        ("l", Severity::Warning, "is 0x32 bytes but links to \"a\" which is 0x64 bytes"),
+        ("n/x", Severity::Error, "past the end of its node"),
+        ("res", Severity::Error, "has a 0x5c byte header but is only 0x50 bytes")
+    ];
+    assert_eq!(diagnostics.len(), expected.len(), "{:?}", diagnostics);
+    for ((path, severity, message), (expected_path, expected_severity, expected_message)) in diagnostics.iter().zip(&expected) {
+        assert_eq!((path.as_str(), severity), (*expected_path, expected_severity));
+        assert!(message.contains(expected_message), "{}", message);
+    }
+
+    // only the data that isn't in the file can't be read
+    assert!(matches!(pak.get_root_node().find_child("far").unwrap().data(), Err(PakError::ChildOutOfRange { .. })));
+    assert_eq!(find_path(pak.get_root_node(), "n/x").data().unwrap()[..100], test_data(1, 100)[..]);
+}
+
+fn open_error(bytes: &[u8]) -> PakError {
+    PakInterface::open_from(MemoryStream::new(bytes)).unwrap_err()
+}
+
+#[test]
+fn malformed_paks_are_refused() {
+    let mut texture = header("texture", 1);
+    texture.meta_data.push(TestMetaData(1, 2, b"name".to_vec()));
+    let node = TestNode { header: header("pak", 1), children: vec![("tex".to_string(), TestEntry::Resource(texture, test_data(1, 10)))] };
+    let input = encode_node(&node);
+    let (tex_offset, _) = child_range(&input, 0, &node, 0);
+    let tex_offset = tex_offset as usize;
+    let patched = |position: usize, bytes: &[u8]| {
+        let mut output = input.clone();
+        output[position..position + bytes.len()].copy_from_slice(bytes);
+        output
+    };
+
+    assert!(matches!(open_error(&patched(0, b"RES2")), PakError::BadMagic { found } if found == u32::from_le_bytes(*b"RES2")));
+    assert!(matches!(open_error(&input[..0x30]), PakError::Io(error) if error.kind() == io::ErrorKind::UnexpectedEof));
+    assert!(matches!(open_error(&input[..0x50]), PakError::Io(error) if error.kind() == io::ErrorKind::UnexpectedEof));
+    // cut in the data, the tables can still be read
+    let truncated = open_bytes(&input[..input.len() - 20]);
+    assert!(matches!(truncated.get_root_node().children()[0].data(), Err(PakError::ChildOutOfRange { .. })));
+    assert!(matches!(open_error(&patched(4, &[0xff, 0xfe])), PakError::InvalidClass { offset: 4, .. }));
+    assert!(matches!(open_error(&patched(0x40, &[2])), PakError::BadDelimiter { offset: 0x40, found: 2 }));
+    assert!(matches!(open_error(&patched(0x4e, &u32::MAX.to_le_bytes())), PakError::Io(error) if error.kind() == io::ErrorKind::UnexpectedEof));
+    assert!(matches!(open_error(&patched(0x41, &2u32.to_le_bytes())),
+        PakError::UnexpectedVersion { offset: 0x41, field: "node version", expected: 0x1000000, found: 2 }));
+
+    // the stored header size of the root, then of the resource
+    assert!(matches!(open_error(&patched(0x3c, &0xffff_fff0u32.to_le_bytes())), PakError::BadHeaderSize { offset: 0, .. }));
+    assert!(matches!(open_error(&patched(0x3c, &8u32.to_le_bytes())), PakError::BadHeaderSize { offset: 0, expected: 0x48, found: 0x40 }));
+    let base = tex_offset as u64;
+    assert!(matches!(open_error(&patched(tex_offset + 0x3c, &u32::MAX.to_le_bytes())), PakError::BadHeaderSize { offset, .. } if offset == base));
+    // a name length that runs past the header isn't allocated
+    assert!(matches!(open_error(&patched(tex_offset + 0x40 + 20, &u32::MAX.to_le_bytes())),
+        PakError::BadHeaderSize { offset, expected: 0x5c, .. } if offset == base));
+    assert!(matches!(open_error(&patched(tex_offset + 0x38, &u32::MAX.to_le_bytes())), PakError::BadHeaderSize { offset, .. } if offset == base));
+}
+
+#[test]
+fn save_as_replaces_the_open_pak_and_keeps_a_backup() {
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("test.pak");
+    let original = encode_node(&sample_node());
+    std::fs::write(&path, &original).unwrap();
+
+    let mut pak = PakInterface::open(File::open(&path).unwrap()).unwrap();
+    pak.get_root_node_mut().find_child_mut("data.bin").unwrap().set_data(test_data(10, 20000));
+    let backup = pak.save_as(&path, None, true).unwrap().unwrap();
+
+    let mut expected = sample_node();
+    set_test_data(&mut expected, "data.bin", test_data(10, 20000));
+    assert_eq!(std::fs::read(&path).unwrap(), encode_node(&expected));
+    assert_eq!(std::fs::read(&backup).unwrap(), original);
+    // the pak now reads from the saved file
+    assert_eq!(pak.get_root_node().find_child("data.bin").unwrap().data().unwrap(), test_data(10, 20000));
+    assert_eq!(save_bytes(&mut pak), encode_node(&expected));
+
+    // the previous backup is replaced
+    node_mut(&mut pak, &["scripts.ssl_block"]).find_child_mut("a.ssl").unwrap().set_data(test_data(11, 10));
+    pak.save_as(&path, Some(6), true).unwrap();
+    assert_eq!(std::fs::read(&backup).unwrap(), encode_node(&expected));
+    assert_eq!(std::fs::read_dir(directory.path()).unwrap().count(), 2);
+}
+
+#[test]
+fn failed_verification_leaves_the_target_untouched() {
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("test.pak");
+    let original = encode_node(&sample_node());
+    std::fs::write(&path, &original).unwrap();
+
+    // raw data that starts with a header is read back as a resource
+    let mut pak = PakInterface::open(File::open(&path).unwrap()).unwrap();
+    let data = [encode_header(&header("texture", 5)), test_data(12, 100)].concat();
+    pak.get_root_node_mut().find_child_mut("data.bin").unwrap().set_data(data.clone());
+    let error = pak.save_as(&path, None, true).unwrap_err();
+    assert!(error.to_string().contains("\"data.bin\" was saved with different contents"), "{}", error);
+    assert_eq!(std::fs::read(&path).unwrap(), original);
+    assert_eq!(std::fs::read_dir(directory.path()).unwrap().count(), 1);
+    assert_eq!(pak.get_root_node().find_child("data.bin").unwrap().data().unwrap(), data);
+}
+
+#[test]
+fn verification_compares_rewritten_data() {
+    let mut pak = open_bytes(&encode_node(&sample_node()));
+    pak.get_root_node_mut().find_child_mut("data.bin").unwrap().set_data(test_data(10, 20000));
+    let mut same = sample_node();
+    set_test_data(&mut same, "data.bin", test_data(10, 20000));
+    save_as::verify_node(pak.get_root_node(), open_bytes(&encode_node(&same)).get_root_node(), "").unwrap();
+
+    // same size, other bytes
+    let mut different = sample_node();
+    set_test_data(&mut different, "data.bin", test_data(11, 20000));
+    let error = save_as::verify_node(pak.get_root_node(), open_bytes(&encode_node(&different)).get_root_node(), "").unwrap_err();
+    assert_eq!(error.to_string(), "Saved pak doesn't match, \"data.bin\" was saved with different data");
+}
+
+#[test]
+fn patching_reports_each_file() {
+    let node = TestNode {
+        header: header("pak", 1),
+        children: vec![
+            ("data.bin".to_string(), TestEntry::Data(test_data(1, 100))),
+            ("<texture>\\a.tex".to_string(), TestEntry::Resource(header("texture", 2), test_data(2, 100))),
+            ("<bitmap>\\a.tex".to_string(), TestEntry::Resource(header("bitmap", 3), test_data(3, 100))),
+            ("scripts.ssl_block".to_string(), TestEntry::Node(TestNode {
+                header: header("ssl_block", 4),
+                children: vec![("b.ssl".to_string(), TestEntry::Data(test_data(4, 100)))]
+            }))
+        ]
+    };
+    let directory = tempfile::tempdir().unwrap();
+    std::fs::create_dir(directory.path().join("scripts")).unwrap();
+    std::fs::write(directory.path().join("Data.BIN"), test_data(5, 10)).unwrap();
+    std::fs::write(directory.path().join("a.tex"), test_data(6, 20)).unwrap();
+    std::fs::write(directory.path().join("scripts/b.ssl"), test_data(7, 30)).unwrap();
+    std::fs::write(directory.path().join("scripts/c.ssl"), test_data(8, 40)).unwrap();
+
+    let mut pak = open_bytes(&encode_node(&node));
+    let report = crate::dump::patch_from_directory(pak.get_root_node_mut(), directory.path()).unwrap();
+    let replaced: Vec<_> = report.replaced.iter().map(|(entry, path)| (entry.as_str(), path.strip_prefix(directory.path()).unwrap())).collect();
+    assert_eq!(replaced, [
+        ("<texture>\\a.tex", std::path::Path::new("a.tex")),
+        ("<bitmap>\\a.tex", std::path::Path::new("a.tex")),
+        ("data.bin", std::path::Path::new("Data.BIN")),
+        ("scripts.ssl_block/b.ssl", std::path::Path::new("scripts/b.ssl"))
+    ]);
+    assert_eq!(report.unmatched_files, [directory.path().join("scripts/c.ssl")]);
+    assert_eq!(report.ambiguous, [(directory.path().join("a.tex"), vec!["<texture>\\a.tex".to_string(), "<bitmap>\\a.tex".to_string()])]);
+
+    let root = pak.get_root_node();
+    assert_eq!(root.children()[0].data().unwrap(), test_data(5, 10));
+    assert_eq!(root.children()[1].data().unwrap(), test_data(6, 20));
+    assert_eq!(root.children()[2].data().unwrap(), test_data(6, 20));
+    assert_eq!(find_path(root, "scripts.ssl_block/b.ssl").data().unwrap(), test_data(7, 30));
+    assert!(report.to_string().ends_with("4 entries replaced, 1 files unmatched, 1 ambiguous"));
+}
+
+#[test]
+fn children_smaller_than_a_header_are_data() {
+    let short_header = encode_header(&header("texture", 1))[..0x30].to_vec();
+    let node = TestNode {
+        header: header("pak", 1),
+        children: vec![
+            ("short".to_string(), TestEntry::Data(short_header.clone())),
+            ("after".to_string(), TestEntry::Data(test_data(1, 0x40))),
+            ("last".to_string(), TestEntry::Data(b"RE".to_vec()))
+        ]
+    };
+    let input = encode_node(&node);
+    let mut pak = open_bytes(&input);
+    let root = pak.get_root_node();
+    assert!(matches!(root.children()[0].contents(), ResourceType::Data));
+    assert_eq!(root.children()[0].data().unwrap(), short_header);
+    assert_eq!(root.children()[2].data().unwrap(), b"RE");
+    assert!(pak.validate().is_empty());
+    assert_eq!(save_bytes(&mut pak), input);
+}
+
+#[test]
+fn duplicate_names_round_trip_through_a_dump() {
+    let node = TestNode {
+        header: header("pak", 1),
+        children: vec![
+            ("a.ssl".to_string(), TestEntry::Data(test_data(1, 100))),
+            ("a.ssl".to_string(), TestEntry::Data(test_data(2, 100))),
+            ("<texture>\\a.tex".to_string(), TestEntry::Resource(header("texture", 2), test_data(3, 100))),
+            ("<bitmap>\\a.tex".to_string(), TestEntry::Resource(header("bitmap", 3), test_data(4, 100)))
+        ]
+    };
+    let directory = tempfile::tempdir().unwrap();
+    let mut pak = open_bytes(&encode_node(&node));
+    crate::dump::extract_all(pak.get_root_node(), directory.path()).unwrap();
+    for (file, seed) in &[("a.ssl", 5), ("a.ssl#1", 6), ("a.tex", 7), ("a.tex#1", 8)] {
+        std::fs::write(directory.path().join(file), test_data(*seed, 50)).unwrap();
+    }
+
+    let report = crate::dump::patch_from_directory(pak.get_root_node_mut(), directory.path()).unwrap();
+    assert_eq!(report.replaced.len(), 4);
+    assert!(report.unmatched_files.is_empty() && report.ambiguous.is_empty(), "{}", report);
+    let data: Vec<_> = pak.get_root_node().children().iter().map(|child| child.data().unwrap()).collect();
+    assert_eq!(data, [test_data(5, 50), test_data(6, 50), test_data(7, 50), test_data(8, 50)]);
+}
+
+#[test]
+fn dumps_pack_back_into_the_same_pak() {
+    let mut node = sample_node();
+    node.children.push(("nested link".to_string(), TestEntry::Link(4)));
+    let bytes = encode_node(&node);
+    let directory = tempfile::tempdir().unwrap();
+    crate::dump::extract_all(open_bytes(&bytes).get_root_node(), directory.path()).unwrap();
+
+    let mut packed = crate::dump::pack(directory.path()).unwrap();
+    assert_eq!(save_bytes(&mut packed), bytes);
+}
+
+/// Lays out `data` in the compressed container by hand, one chunk per size in `chunk_sizes`
+fn compress_chunks(data: &[u8], chunk_sizes: &[usize]) -> Vec<u8> {
+    assert_eq!(chunk_sizes.iter().sum::<usize>(), data.len());
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    for size in chunk_sizes {
+        let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::new(6));
+        encoder.write_all(&data[start..start + size]).unwrap();
+        chunks.push([(*size as u32).to_le_bytes().to_vec(), encoder.finish().unwrap()].concat());
+        start += size;
+    }
+
+    let mut container = (chunks.len() as u32).to_le_bytes().to_vec();
+    let mut offset = 4 + chunks.len() as u64 * 8;
+    for chunk in &chunks {
+        container.extend(&offset.to_le_bytes());
+        offset += chunk.len() as u64;
+    }
+    [container, chunks.concat()].concat()
+}
+
+fn compressed_input(container: Vec<u8>) -> pak_io::InputFile {
+    pak_io::InputFile::Compressed(pak_io::CompressedFile::new(pak_io::InputFile::Memory(container)).unwrap())
+}
+
+#[test]
+fn compressed_container_layout() {
+    // written out by hand, the zlib streams come from another encoder than flate2
+    let container: Vec<u8> = [
+        // chunk count
+        &[0x02, 0x00, 0x00, 0x00][..],
+        // file offsets of the chunks
+        &[0x14, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+        &[0x2d, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+        // decompressed size and zlib stream of each chunk
+        &[0x0d, 0x00, 0x00, 0x00],
+        &[0x78, 0xda, 0x4b, 0x54, 0x48, 0xce, 0xcf, 0x2d, 0x28, 0x4a, 0x2d, 0x2e, 0x4e, 0x4d, 0x51, 0x00, 0x00, 0x22, 0x04, 0x04, 0xd7],
+        &[0x03, 0x00, 0x00, 0x00],
+        &[0x78, 0xda, 0x2b, 0x48, 0xcc, 0x06, 0x00, 0x02, 0x80, 0x01, 0x3d]
+    ].concat();
+
+    let input = compressed_input(container);
+    assert_eq!(input.length().unwrap(), 16);
+    let mut buffer = [0u8; 16];
+    input.read_at(0, &mut buffer).unwrap();
+    assert_eq!(&buffer, b"a compressed pak");
+}
+
+#[test]
+fn compressed_reads_cross_chunk_boundaries() {
+    const CHUNK: usize = pak_io::COMPRESSED_CHUNK_SIZE;
+    let data = test_data(1, 2 * CHUNK + 1005);
+    // the writer only makes full chunks but the reader takes any sizes
+    for chunk_sizes in &[vec![CHUNK, CHUNK, 1005], vec![1000, CHUNK, 5, CHUNK]] {
+        let input = compressed_input(compress_chunks(&data, chunk_sizes));
+        assert_eq!(input.length().unwrap(), data.len() as u64);
+
+        // backwards so every read switches chunks
+        for (offset, size) in &[(2 * CHUNK + 1000, 5), (CHUNK - 10, CHUNK + 20), (995, 10), (1000, 1), (0, data.len())] {
+            let mut buffer = vec![0u8; *size];
+            input.read_at(*offset as u64, &mut buffer).unwrap();
+            assert_eq!(buffer, &data[*offset..offset + size]);
+        }
+        let mut buffer = [0u8; 2];
+        assert!(input.read_at(data.len() as u64 - 1, &mut buffer).is_err());
+    }
+}
+
+#[test]
+fn compressed_data_can_end_on_a_chunk() {
+    const CHUNK: usize = pak_io::COMPRESSED_CHUNK_SIZE;
+    let data = test_data(2, 2 * CHUNK);
+    let input = compressed_input(compress_chunks(&data, &[CHUNK, CHUNK]));
+    assert_eq!(input.length().unwrap(), 2 * CHUNK as u64);
+    let mut buffer = [0u8; 16];
+    input.read_at(2 * CHUNK as u64 - 16, &mut buffer).unwrap();
+    assert_eq!(buffer, data[2 * CHUNK - 16..]);
+    input.read_at(2 * CHUNK as u64, &mut []).unwrap();
+    assert!(input.read_at(2 * CHUNK as u64, &mut buffer[..1]).is_err());
+
+    // a pak that ends on the chunk size
+    let mut node = TestNode { header: header("pak", 1), children: vec![("data".to_string(), TestEntry::Data(Vec::new()))] };
+    let padding = 2 * CHUNK - encode_node(&node).len();
+    node.children[0].1 = TestEntry::Data(test_data(3, padding));
+    let pak = encode_node(&node);
+    assert_eq!(pak.len(), 2 * CHUNK);
+    let mut decompressed = PakInterface::open_from(MemoryStream::new(compress_chunks(&pak, &[CHUNK, CHUNK]))).unwrap();
+    assert_eq!(save_bytes(&mut decompressed), pak);
+}
+
+#[test]
+fn empty_compressed_paks_can_be_read() {
+    let pak = encode_node(&TestNode { header: header("pak", 1), children: Vec::new() });
+    let mut decompressed = PakInterface::open_from(MemoryStream::new(compress_chunks(&pak, &[pak.len()]))).unwrap();
+    assert!(decompressed.get_root_node().children().is_empty());
+    assert_eq!(save_bytes(&mut decompressed), pak);
+
+    // a container without chunks isn't a pak
+    assert!(matches!(PakInterface::open_from(MemoryStream::new(&[0u8; 4][..])), Err(PakError::BadMagic { found: 0 })));
+}
+
+#[test]
+fn compressed_writer_seeks_across_chunks() {
+    const CHUNK: usize = pak_io::COMPRESSED_CHUNK_SIZE;
+    let mut writer = pak_io::CompressedWriter::new(MemoryStream::new(Vec::new()), 6).unwrap();
+    let mut expected = test_data(1, 2 * CHUNK + 100);
+    writer.write_bytes(&expected).unwrap();
+
+    // patch back over a chunk boundary, then skip past the end, the gap reads as zero
+    writer.seek(CHUNK as u64 - 4).unwrap();
+    writer.write_bytes(&[1; 8]).unwrap();
+    expected[CHUNK - 4..CHUNK + 4].copy_from_slice(&[1; 8]);
+    writer.seek(3 * CHUNK as u64 + 10).unwrap();
+    writer.write_bytes(&[2; 6]).unwrap();
+    expected.resize(3 * CHUNK + 10, 0);
+    expected.extend(&[2; 6]);
+
+    writer.seek(CHUNK as u64 - 8).unwrap();
+    assert_eq!(writer.read_vector(16).unwrap(), &expected[CHUNK - 8..CHUNK + 8]);
+    writer.seek(0).unwrap();
+    assert_eq!(writer.read_vector(expected.len()).unwrap(), expected);
+    assert_eq!(writer.length().unwrap(), expected.len() as u64);
+
+    let container = writer.finish().unwrap().into_inner();
+    assert_eq!(container[..4], 4u32.to_le_bytes());
+    let input = compressed_input(container);
+    let mut buffer = vec![0u8; expected.len()];
+    input.read_at(0, &mut buffer).unwrap();
+    assert_eq!(buffer, expected);
+}
+
+#[test]
+fn compressed_writer_handles_chunk_sized_and_empty_output() {
+    const CHUNK: usize = pak_io::COMPRESSED_CHUNK_SIZE;
+    let mut writer = pak_io::CompressedWriter::new(MemoryStream::new(Vec::new()), 6).unwrap();
+    writer.write_bytes(&test_data(2, 2 * CHUNK)).unwrap();
+    let container = writer.finish().unwrap().into_inner();
+    assert_eq!(container[..4], 2u32.to_le_bytes());
+    assert_eq!(compressed_input(container).length().unwrap(), 2 * CHUNK as u64);
+
+    // the reader refuses a container without chunks, so the writer doesn't make one
+    let writer = pak_io::CompressedWriter::new(MemoryStream::new(Vec::new()), 6).unwrap();
+    assert_eq!(writer.finish().unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+    // whole paks, one without children and one ending on the chunk size
+    let mut node = TestNode { header: header("pak", 1), children: Vec::new() };
+    let empty = encode_node(&node);
+    let mut decompressed = open_bytes(&save_compressed_bytes(&mut open_bytes(&empty), 6));
+    assert!(decompressed.get_root_node().children().is_empty());
+    assert_eq!(save_bytes(&mut decompressed), empty);
+
+    node.children.push(("data".to_string(), TestEntry::Data(Vec::new())));
+    let padding = 2 * CHUNK - encode_node(&node).len();
+    node.children[0].1 = TestEntry::Data(test_data(3, padding));
+    let pak = encode_node(&node);
+    let compressed = save_compressed_bytes(&mut open_bytes(&pak), 6);
+    assert_eq!(compressed[..4], 2u32.to_le_bytes());
+    assert_eq!(save_bytes(&mut open_bytes(&compressed)), pak);
+}
@@ -0,0 +1,41 @@
+use std::collections::HashMap;
+use crate::util::join_node_path;
+use super::{PakInterface, ResourceNode, ResourceType};
+
+/// An entry carrying a uuid, the root node has an empty path
+#[derive(Debug, Clone)]
+pub struct UuidMatch {
+    /// node path, the names of the parent nodes and the entry joined by '/'
+    pub path: String,
+    pub class: String
+}
+
+pub type UuidIndex = HashMap<u128, Vec<UuidMatch>>;
+
+fn index_node(node: &ResourceNode, node_path: &str, index: &mut UuidIndex) {
+    for child in node.children() {
+        let path = join_node_path(node_path, &child.name);
+        let header = match &child.contents {
+            ResourceType::Resource(header) => header,
+            ResourceType::Node(child_node) => {
+                index_node(child_node, &path, index);
+                &child_node.header
+            },
+            _ => continue
+        };
+        index.entry(header.uuid).or_default().push(UuidMatch { path: path, class: header.class.clone() });
+    }
+}
+
+impl PakInterface {
+    /// Every entry with the uuid, the index of the whole pak is built on first use
+    pub fn find_by_uuid(&self, uuid: u128) -> Vec<UuidMatch> {
+        let uuid_index = self.uuid_index.get_or_init(|| {
+            let mut index = UuidIndex::new();
+            index.insert(self.root_node.header.uuid, vec![UuidMatch { path: String::new(), class: self.root_node.header.class.clone() }]);
+            index_node(&self.root_node, "", &mut index);
+            index
+        });
+        uuid_index.get(&uuid).cloned().unwrap_or_default()
+    }
+}
@@ -0,0 +1,137 @@
+use std::fmt;
+use crate::util::join_node_path;
+use super::{PakInterface, ResourceNode, ResourceType, NODE_CLASSES, RESOURCE_HEADER_SIZE};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// Unusual but the pak can be read and written
+    Warning,
+    /// The pak is damaged, saving it may lose or duplicate data
+    Error
+}
+
+/// A problem found in the layout of a pak
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// node path of the entry, the names of the parent nodes and the entry joined by '/'
+    pub path: String,
+    pub message: String
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error"
+        };
+        if self.path.is_empty() {
+            write!(f, "{}: {}", severity, self.message)
+        } else {
+            write!(f, "{}: \"{}\" {}", severity, self.path, self.message)
+        }
+    }
+}
+
+impl ResourceNode {
+    /// Checks the children as they are laid out in the input file, new entries are skipped.
+    /// Nested nodes that weren't read yet are only checked with `read_all`.
+    fn validate(&self, node_path: &str, read_all: bool, diagnostics: &mut Vec<Diagnostic>) {
+        // only the root can end up with another class, nested nodes are recognised by their class
+        if !NODE_CLASSES.contains(&&self.header.class[..]) {
+            diagnostics.push(Diagnostic { severity: Severity::Error, path: node_path.to_string(), message: format!("node has class \"{}\" which isn't a node class", self.header.class) });
+        }
+        if !self.header.meta_data.is_empty() || self.header.size as u64 != RESOURCE_HEADER_SIZE {
+            diagnostics.push(Diagnostic { severity: Severity::Warning, path: node_path.to_string(), message: format!("node header has {} metadata entries", self.header.meta_data.len()) });
+        }
+        let tables = match self.tables() {
+            Ok(tables) => tables,
+            Err(error) => {
+                diagnostics.push(Diagnostic { severity: Severity::Error, path: node_path.to_string(), message: format!("node can't be read, {}", error) });
+                return;
+            }
+        };
+
+        let mut report = |severity, index: usize, message: String| {
+            diagnostics.push(Diagnostic { severity: severity, path: join_node_path(node_path, &tables.children[index].name), message: message });
+        };
+
+        let node_size = tables.header_len + tables.data_len;
+        let mut ranges = Vec::new();
+        for (index, child) in tables.children.iter().enumerate() {
+            if child.is_new_entry {
+                continue;
+            }
+            if child.dangling_link {
+                report(Severity::Error, index, format!("is a link to offset {:#x} but no entry starts there", child.offset));
+            }
+
+            match &child.contents {
+                ResourceType::Link(target) => {
+                    let target = &tables.children[*target];
+                    if target.size != child.size {
+                        report(Severity::Warning, index, format!("is {:#x} bytes but links to \"{}\" which is {:#x} bytes", child.size, target.name, target.size));
+                    }
+                    continue;
+                },
+                ResourceType::Resource(header) if header.size > child.size => {
+                    report(Severity::Error, index, format!("has a {:#x} byte header but is only {:#x} bytes", header.size, child.size));
+                },
+                _ => {}
+            }
+
+            if child.out_of_range {
+                report(Severity::Error, index, format!("is {:#x} bytes at offset {:#x}, past the end of the file", child.size, child.offset));
+                continue;
+            }
+            if child.size == 0 || child.dangling_link {
+                continue;
+            }
+            let end = child.offset + child.size as u64;
+            if child.offset < tables.header_len {
+                report(Severity::Error, index, format!("starts at offset {:#x}, inside the node tables which end at {:#x}", child.offset, tables.header_len));
+            }
+            if end > node_size {
+                report(Severity::Error, index, format!("ends at offset {:#x}, past the end of its node at {:#x}", end, node_size));
+            }
+            ranges.push((child.offset, end, index));
+        }
+
+        ranges.sort();
+        for pair in ranges.windows(2) {
+            let (_, end, index) = pair[0];
+            let (next_offset, _, next_index) = pair[1];
+            if next_offset < end {
+                report(Severity::Error, next_index, format!("overlaps \"{}\" by {:#x} bytes", tables.children[index].name, end - next_offset));
+            }
+        }
+
+        for child in &tables.children {
+            if let ResourceType::Node(child_node) = &child.contents {
+                if !child.is_new_entry && (read_all || child_node.is_loaded()) {
+                    child_node.validate(&join_node_path(node_path, &child.name), read_all, diagnostics);
+                }
+            }
+        }
+    }
+}
+
+impl PakInterface {
+    /// Checks the layout of the pak, the tree is only checked as far as it comes from the input file
+    pub fn validate(&self) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+        if self.input_file.is_some() {
+            self.root_node.validate("", true, &mut diagnostics);
+        }
+        diagnostics
+    }
+
+    /// Same as `validate` but without reading nested nodes that weren't used yet
+    pub fn validate_loaded(&self) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+        if self.input_file.is_some() {
+            self.root_node.validate("", false, &mut diagnostics);
+        }
+        diagnostics
+    }
+}
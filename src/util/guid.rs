@@ -0,0 +1,57 @@
+use std::{fmt, str::FromStr};
+
+/// A u128 read from a pak shown as a Windows GUID, the first three groups are little endian
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Guid(pub u128);
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let b = self.0.to_le_bytes();
+        write!(f, "{:02x}{:02x}{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-",
+            b[3], b[2], b[1], b[0], b[5], b[4], b[7], b[6], b[8], b[9])?;
+        for byte in &b[10..] {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseGuidError(String);
+
+impl fmt::Display for ParseGuidError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Bad GUID \"{}\", expected xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx", self.0)
+    }
+}
+
+impl std::error::Error for ParseGuidError {}
+
+impl FromStr for Guid {
+    type Err = ParseGuidError;
+
+    /// Parses the form `Display` writes, optionally in braces
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let error = || ParseGuidError(value.to_string());
+        let trimmed = value.strip_prefix('{').and_then(|rest| rest.strip_suffix('}')).unwrap_or(value);
+        let groups: Vec<&str> = trimmed.split('-').collect();
+        let group_lengths: Vec<usize> = groups.iter().map(|group| group.len()).collect();
+        if group_lengths != [8, 4, 4, 4, 12] || !trimmed.chars().all(|c| c == '-' || c.is_ascii_hexdigit()) {
+            return Err(error());
+        }
+
+        let digits: String = groups.concat();
+        let mut text_bytes = [0u8; 16];
+        for (index, byte) in text_bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&digits[index * 2..index * 2 + 2], 16).map_err(|_| error())?;
+        }
+        let t = text_bytes;
+        let bytes = [t[3], t[2], t[1], t[0], t[5], t[4], t[7], t[6], t[8], t[9], t[10], t[11], t[12], t[13], t[14], t[15]];
+        Ok(Guid(u128::from_le_bytes(bytes)))
+    }
+}
+
+/// Parses a uuid written as a GUID
+pub fn parse_uuid(value: &str) -> Option<u128> {
+    value.parse::<Guid>().ok().map(|guid| guid.0)
+}
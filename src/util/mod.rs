@@ -1,4 +1,6 @@
 use std::{io};
+mod guid;
+pub use guid::{Guid, parse_uuid};
 
 pub const fn u32_from_str(input: &'static str) -> u32 {
     let bytes = input.as_bytes();
@@ -65,6 +67,10 @@ pub trait BinaryStream {
     /// Read a dynamic length string starting with the length as a u32
     fn read_string(&mut self) -> io::Result<String> {
         let string_len = self.read_u32()? as usize;
+        // don't allocate a garbled length before the read fails
+        if self.position()? + string_len as u64 > self.length()? {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "String runs past the end of the stream"));
+        }
     
         let mut data = vec![0u8; string_len];
         self.read_bytes(&mut data)?;
@@ -125,6 +131,11 @@ pub trait BinaryStream {
     }
 }
 
+/// Path of the child `name` of the node at `node_path`, the root node has an empty path
+pub(crate) fn join_node_path(node_path: &str, name: &str) -> String {
+    if node_path.is_empty() { name.to_string() } else { format!("{}/{}", node_path, name) }
+}
+
 pub fn string_length(data: &[u8]) -> usize {
     for offset in 0..data.len() {
         if data[offset] == 0 {